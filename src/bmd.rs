@@ -43,24 +43,38 @@ fn read_uint32_le(buf: &[u8]) -> u32 {
   ((buf[3] as u32) << 24) + ((buf[2] as u32) << 16) + ((buf[1] as u32) << 8) + buf[0] as u32
 }
 
-fn read_bmd_header(buf: &[u8]) -> (&[u8], BmdHeader) {
+fn read_bmd_header(buf: &[u8]) -> Result<(&[u8], BmdHeader), &'static str> {
+  if buf.len() < 0x24 {
+    return Err("read_bmd_header: BMD is truncated.");
+  }
+
   let header = BmdHeader {
     num_frames: read_uint32_le(&buf[12..16]) as usize,
     num_pixels: read_uint32_le(&buf[16..20]) as usize,
     num_rows: read_uint32_le(&buf[20..24]) as usize,
   };
 
-  (&buf[0x24..], header)
+  Ok((&buf[0x24..], header))
 }
 
-fn read_frames<'a>(buf: &'a[u8], frames: &mut [BmdFrameInfo]) -> Result<&'a[u8], &'static str> {
-  if buf[0] != 0xE9 || buf[1] != 0x03 {
-    return Err("read_frames: starting point is incorrect.");
+/// Splits a section into its body and whatever follows it.
+fn read_section(buf: &[u8]) -> Result<(&[u8], &[u8]), &'static str> {
+  if buf.len() < 12 || buf[0] != 0xE9 || buf[1] != 0x03 {
+    return Err("read_section: starting point is incorrect.");
   }
 
   let section_length = read_uint32_le(&buf[0x08..]) as usize;
+  if buf.len() - 12 < section_length {
+    return Err("read_section: section is truncated.");
+  }
+
+  Ok((&buf[12..12 + section_length], &buf[12 + section_length..]))
+}
 
-  for (ch, f) in buf[12..section_length + 12].chunks(24).zip(frames.iter_mut()) {
+fn read_frames<'a>(buf: &'a[u8], frames: &mut [BmdFrameInfo]) -> Result<&'a[u8], &'static str> {
+  let (section, rest) = read_section(buf)?;
+
+  for (ch, f) in section.chunks_exact(24).zip(frames.iter_mut()) {
     f.frame_type = read_uint32_le(&ch);
     f.dx = read_uint32_le(&ch[4..]) as i32;
     f.dy = read_uint32_le(&ch[8..]) as i32;
@@ -69,98 +83,108 @@ fn read_frames<'a>(buf: &'a[u8], frames: &mut [BmdFrameInfo]) -> Result<&'a[u8],
     f.off = read_uint32_le(&ch[20..]) as usize;
   }
 
-  Ok(&buf[section_length + 12..])
-}
-
-fn skip_section(buf: &[u8]) -> &[u8] {
-  let section_length = read_uint32_le(&buf[0x08..]) as usize;
-  &buf[12 + section_length..]
+  Ok(rest)
 }
 
 fn read_rows<'a>(buf: &'a[u8], rows: &mut [BmdRowInfo]) -> Result<&'a[u8], &'static str> {
-  if buf[0] != 0xE9 || buf[1] != 0x03 {
-    return Err("read_frames: starting point is incorrect.");
-  }
+  let (section, rest) = read_section(buf)?;
 
-  let section_length = read_uint32_le(&buf[0x08..]) as usize;
-  let mut rest: &[u8] = &buf[12..];
-
-  for i in 0..(section_length / 4) {
-    let u = read_uint32_le(&rest);
-    rows[i].raw = u;
-    rows[i].indent = (u >> 22) as usize;
-    rows[i].offset = (u & ((1 << 22) - 1)) as usize;
-    rest = &rest[4..];
+  for (ch, r) in section.chunks_exact(4).zip(rows.iter_mut()) {
+    let u = read_uint32_le(ch);
+    r.raw = u;
+    r.indent = (u >> 22) as usize;
+    r.offset = (u & ((1 << 22) - 1)) as usize;
   }
 
-  Ok(&rest)
+  Ok(rest)
 }
 
-fn read_pixels<'a>(buf: &'a[u8]) -> Result<(&'a[u8], &'a[u8]), &'static str> {
-  if buf[0] != 0xE9 || buf[1] != 0x03 {
-    return Err("read_pixels: starting point is incorrect.");
-  }
+fn read_pixels(buf: &[u8]) -> Result<(&[u8], &[u8]), &'static str> {
+  let (section, rest) = read_section(buf)?;
 
-  let section_length = read_uint32_le(&buf[0x08..]) as usize;
+  Ok((rest, section))
+}
+
+/// Splits the BMD at the start of `buf` from whatever follows it, usually
+/// its shadow BMD or the next object.
+pub fn bmd_split(buf: &[u8]) -> Result<(&[u8], &[u8]), &'static str> {
+  let (rest, _) = read_bmd_header(buf)?;
+  let (_, rest) = read_section(rest)?;
+  let (_, rest) = read_section(rest)?;
+  let (_, rest) = read_section(rest)?;
 
-  Ok((&buf[section_length + 12..], &buf[12..12 + section_length]))
+  Ok(buf.split_at(buf.len() - rest.len()))
 }
 
-pub fn bmd_stats(buf: &[u8], has_shadow: &[u8], count: usize) -> Vec<BmdStats> {
-  let mut remaining_slice = buf;
-  let mut bmd_stats_vec = vec![BmdStats { frames: 0, width: 0, height: 0, encoded_length: 0 }; count];
-
-  for i in 0..count {
-    let (rest, header) = read_bmd_header(remaining_slice);
-    let mut frames = vec![BmdFrameInfo { frame_type: 0, dx: 0, dy: 0, width: 0, len: 0, off: 0 }; header.num_frames];
-    let mut shadow_frames: Option<Vec<BmdFrameInfo>> = None;
-    let rest = read_frames(rest, &mut frames[..]).expect("read_frames failed");
-    let rest = skip_section(rest);
-    let rest = skip_section(rest);
-    remaining_slice = rest;
-
-    if has_shadow[i] > 0 {
-      let (rest, header) = read_bmd_header(remaining_slice);
-      let mut fv = vec![BmdFrameInfo { frame_type: 0, dx: 0, dy: 0, width: 0, len: 0, off: 0 }; header.num_frames];
-      let rest = read_frames(rest, &mut fv[..]).expect("read_frames failed");
-      let rest = skip_section(rest);
-      let rest = skip_section(rest);
-      remaining_slice = rest;
-
-      shadow_frames = Some(fv);
-    }
+fn frame_infos(buf: &[u8]) -> Result<Vec<BmdFrameInfo>, &'static str> {
+  let (rest, header) = read_bmd_header(buf)?;
+  let mut frames = vec![BmdFrameInfo { frame_type: 0, dx: 0, dy: 0, width: 0, len: 0, off: 0 }; header.num_frames];
+  read_frames(rest, &mut frames[..])?;
 
-    let mut stat = &mut bmd_stats_vec[i];
-    stat.frames += header.num_frames;
+  Ok(frames)
+}
+
+/// Frame size that fits every frame of `buf`, merged with its shadow.
+pub fn bmd_stat(buf: &[u8], shadow: Option<&[u8]>) -> Result<BmdStats, &'static str> {
+  let frames = frame_infos(buf)?;
+  let mut stat = BmdStats { width: 0, height: 0, frames: frames.len(), encoded_length: 0 };
 
-    if let Some(s_frames) = shadow_frames {
-      for (f, fs) in frames.iter().zip(s_frames.iter()) {
-        let x0 = cmp::min(f.dx, fs.dx);
-        let y0 = cmp::min(f.dy, fs.dy);
-        let x1 = cmp::max(f.width as i32 + f.dx, fs.width as i32 + fs.dx);
-        let y1 = cmp::max(f.width as i32 + f.dx, fs.width as i32 + fs.dx);
+  if let Some(shadow) = shadow {
+    for (f, fs) in frames.iter().zip(frame_infos(shadow)?.iter()) {
+      let x0 = cmp::min(f.dx, fs.dx);
+      let y0 = cmp::min(f.dy, fs.dy);
+      let x1 = cmp::max(f.width as i32 + f.dx, fs.width as i32 + fs.dx);
+      let y1 = cmp::max(f.len as i32 + f.dy, fs.len as i32 + fs.dy);
 
-        stat.width = cmp::max(stat.width, (x1 - x0) as usize);
-        stat.height = cmp::max(stat.height, (y1 - y0) as usize);
+      stat.width = cmp::max(stat.width, (x1 - x0) as usize);
+      stat.height = cmp::max(stat.height, (y1 - y0) as usize);
+    }
+  } else {
+    for f in frames {
+      if stat.width < f.width {
+        stat.width = f.width;
       }
-    } else {
-      for f in frames {
-        if stat.width < f.width {
-          stat.width = f.width;
-        }
-        if stat.height < f.len {
-          stat.height = f.len;
-        }
+      if stat.height < f.len {
+        stat.height = f.len;
       }
     }
+  }
+
+  // stat.width += stat.width % 4;
+  // stat.height += stat.height % 4;
+
+  stat.encoded_length = 4 * stat.width * stat.height; // calc_output_size(stat.width as u32, stat.height as u32);
+
+  Ok(stat)
+}
+
+/// Stats of `count` BMDs stored back to back in `buf`, each followed by its
+/// shadow where `has_shadow` is set.
+pub fn bmd_stats(buf: &[u8], has_shadow: &[u8], count: usize) -> Result<Vec<BmdStats>, &'static str> {
+  if has_shadow.len() < count {
+    return Err("bmd_stats: need one shadow flag per BMD.");
+  }
+
+  let mut rest = buf;
+  let mut stats = Vec::with_capacity(count);
+
+  for &shadow in has_shadow.iter().take(count) {
+    let (bmd, after) = bmd_split(rest)?;
+    rest = after;
 
-    // stat.width += stat.width % 4;
-    // stat.height += stat.height % 4;
+    let shadow = match shadow {
+      0 => None,
+      _ => {
+        let (shadow, after) = bmd_split(rest)?;
+        rest = after;
+        Some(shadow)
+      },
+    };
 
-    stat.encoded_length = 4 * stat.width * stat.height; // calc_output_size(stat.width as u32, stat.height as u32);
+    stats.push(bmd_stat(bmd, shadow)?);
   }
 
-  return bmd_stats_vec;
+  Ok(stats)
 }
 
 #[inline]
@@ -174,30 +198,39 @@ fn write_uint32_le(buf: &mut [u8], val: u32) {
 macro_rules! bmd {
   ($e:expr) => {
     {
-      let (rest, header) = read_bmd_header($e);
+      let (rest, header) = read_bmd_header($e)?;
     
       let mut frames = vec![BmdFrameInfo { frame_type: 0, dx: 0, dy: 0, width: 0, len: 0, off: 0 }; header.num_frames];
-      let rest = read_frames(rest, &mut frames[..]).expect("read_frames failed");
-      let (rest, pixels) = read_pixels(rest).expect("read_pixels failed.");
+      let rest = read_frames(rest, &mut frames[..])?;
+      let (rest, pixels) = read_pixels(rest)?;
       let mut rows = vec![BmdRowInfo { raw: 0, indent: 0, offset: 0 }; header.num_rows];
-      let rest = read_rows(rest, &mut rows[..]).expect("read_rows failed.");
+      let rest = read_rows(rest, &mut rows[..])?;
 
       (frames, (pixels, (rows, rest)))
     }
   };
 }
 
-pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool, buf: &[u8], out: &mut [u8], frame_palette_index: &mut impl std::iter::Iterator<Item = (&'a usize, &'a usize)>, palettes: &Vec<&[u8]>, _debug: bool) -> usize {
+/// Rows and pixel data of one frame, checked against the row table.
+fn frame_data<'a>(f: &BmdFrameInfo, rows: &'a [BmdRowInfo], pixels: &'a [u8]) -> Result<(&'a [BmdRowInfo], &'a [u8]), &'static str> {
+  let frame_rows = rows.get(f.off..f.off + f.len).ok_or("read_bmd: frame rows are out of range.")?;
+  let offset = frame_rows.first().map_or(0, |r| r.offset);
+  let frame_pixels = pixels.get(offset..).ok_or("read_bmd: frame pixels are out of range.")?;
+
+  Ok((frame_rows, frame_pixels))
+}
+
+pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, buf: &[u8], shadow: Option<&[u8]>, out: &mut [u8], frame_palette_index: &mut impl std::iter::Iterator<Item = (&'a usize, &'a usize)>, palettes: &Vec<&[u8]>, _debug: bool) -> Result<usize, &'static str> {
   // if _debug { console::log_2(&"read_bmd: 1".into(), &JsValue::from(has_shadow)); }
-  let (frames, (pixels, (rows, rest))) = bmd!(buf);
+  let (frames, (pixels, (rows, _))) = bmd!(buf);
 
   let mut frame_offset_ptr = 0usize;
   let mut out_pointer: usize = instance_count * 8;
 
   let encoded_frame_length = w * h * 4;
 
-  if has_shadow {
-    let (s_frames, (s_pixels, (s_rows, _))) = bmd!(rest);
+  if let Some(shadow) = shadow {
+    let (s_frames, (s_pixels, (s_rows, _))) = bmd!(shadow);
 
     for (i, (&fi, &pi)) in frame_palette_index.enumerate() {  // .map(|(&fi, &pi)| { (((&s_frames[fi], &frames[fi]), palettes[pi])) })
       // if _debug { console::log_1(&format!("read_bmd #{}: begin - {} - fi: {} - pi: {}", &frames.len(), i, fi, pi).into()); }
@@ -205,7 +238,8 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
       if fi < frames.len() {
 
         let f = &frames[fi];
-        let p = &palettes[pi];
+        let (f_rows, f_pixels) = frame_data(f, &rows, pixels)?;
+        let p = palettes.get(pi).ok_or("read_bmd: palette index is out of range.")?;
 
         if fi >= s_frames.len() {
           write_uint32_le(&mut out[frame_offset_ptr..], f.dx as u32);
@@ -216,14 +250,15 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
             cmp::max(0, f.dx) as usize,
             cmp::max(0, f.dy) as usize,
             f,
-            &rows[f.off..f.off + f.len],
-            &pixels[rows[f.off].offset..],
+            f_rows,
+            f_pixels,
             &mut out[out_pointer..],
             p,
             _debug
           );
         } else {
           let fs = &s_frames[fi];
+          let (fs_rows, fs_pixels) = frame_data(fs, &s_rows, s_pixels)?;
 
           write_uint32_le(&mut out[frame_offset_ptr..], cmp::min(f.dx, fs.dx) as u32);
           write_uint32_le(&mut out[frame_offset_ptr + 4..], cmp::min(f.dy, fs.dy) as u32);
@@ -234,8 +269,8 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
             cmp::max(0, fs.dx - f.dx) as usize,
             cmp::max(0, fs.dy - f.dy) as usize,
             fs,
-            &s_rows[fs.off..fs.off + fs.len],
-            &s_pixels[s_rows[fs.off].offset..],
+            fs_rows,
+            fs_pixels,
             &mut out[out_pointer..],
             p,
             _debug
@@ -245,8 +280,8 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
             cmp::max(0, f.dx - fs.dx) as usize,
             cmp::max(0, f.dy - fs.dy) as usize,
             f,
-            &rows[f.off..f.off + f.len],
-            &pixels[rows[f.off].offset..],
+            f_rows,
+            f_pixels,
             &mut out[out_pointer..],
             p,
             _debug
@@ -265,7 +300,8 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
 
       if fi < frames.len() {
         let f = &frames[fi];
-        let p = &palettes[pi];
+        let (f_rows, f_pixels) = frame_data(f, &rows, pixels)?;
+        let p = palettes.get(pi).ok_or("read_bmd: palette index is out of range.")?;
 
         if _debug { console::log_1(&format!("read_bmd (no shadow) #{}: dx: {}, dy: {}", i, f.dx, f.dy).into()); }
 
@@ -277,8 +313,8 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
           cmp::max(0, f.dx) as usize,
           cmp::max(0, f.dy) as usize,
           f,
-          &rows[f.off..f.off + f.len],
-          &pixels[rows[f.off].offset..],
+          f_rows,
+          f_pixels,
           &mut out[out_pointer..],
           p,
           _debug
@@ -290,7 +326,7 @@ pub fn read_bmd<'a>(w: usize, h: usize, instance_count: usize, has_shadow: bool,
     }
   }
 
-  return out_pointer;
+  Ok(out_pointer)
 }
 
 fn read_bmd_frame(w: usize, p_w: usize, p_h: usize, fi: &BmdFrameInfo, rows: &[BmdRowInfo], pixels: &[u8], out: &mut [u8], palette: &[u8], _debug: bool) {
@@ -353,3 +389,55 @@ fn read_bmd_frame(w: usize, p_w: usize, p_h: usize, fi: &BmdFrameInfo, rows: &[B
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bmd_errors() {
+    let mut buf = vec![0u8; 0x24];
+    assert_eq!(bmd_split(&buf[..0x20]), Err("read_bmd_header: BMD is truncated."));
+    assert_eq!(bmd_split(&buf), Err("read_section: starting point is incorrect."));
+
+    buf.extend_from_slice(&[0xE9, 0x03, 0, 0, 0, 0, 0, 0, 24, 0, 0, 0]);
+    assert_eq!(bmd_split(&buf), Err("read_section: section is truncated."));
+    assert!(bmd_stat(&buf, None).is_err());
+  }
+
+  /// A BMD with one frame of `(dx, dy, width, len)` and no rows or pixels.
+  fn one_frame(dx: i32, dy: i32, width: u32, len: u32) -> Vec<u8> {
+    let mut buf = vec![0u8; 0x24];
+    buf[12] = 1;
+
+    let section = |buf: &mut Vec<u8>, body: &[u8]| {
+      buf.extend_from_slice(&[0xE9, 0x03, 0, 0, 0, 0, 0, 0]);
+      buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+      buf.extend_from_slice(body);
+    };
+    let frame: Vec<u8> = [0, dx as u32, dy as u32, width, len, 0].iter().flat_map(|v| v.to_le_bytes()).collect();
+    section(&mut buf, &frame);
+    section(&mut buf, &[]);
+    section(&mut buf, &[]);
+    buf
+  }
+
+  #[test]
+  fn test_bmd_stat_shadow() {
+    let bmd = one_frame(0, 0, 4, 10);
+    let shadow = one_frame(2, 3, 6, 2);
+
+    let stat = bmd_stat(&bmd, Some(&shadow)).unwrap();
+    assert_eq!((stat.width, stat.height, stat.frames), (8, 10, 1));
+
+    let stat = bmd_stat(&one_frame(0, 0, 4, 2), Some(&one_frame(1, 5, 2, 4))).unwrap();
+    assert_eq!((stat.width, stat.height), (4, 9));
+
+    let mut buf = bmd.clone();
+    buf.extend_from_slice(&shadow);
+    buf.extend_from_slice(&one_frame(0, 0, 3, 5));
+    let stats = bmd_stats(&buf, &[1, 0], 2).unwrap();
+    assert_eq!(stats.iter().map(|s| (s.width, s.height, s.frames)).collect::<Vec<_>>(), vec![(8, 10, 1), (3, 5, 1)]);
+    assert!(bmd_stats(&buf, &[0, 0, 0, 0], 4).is_err());
+  }
+}
@@ -0,0 +1,431 @@
+use crate::codepage;
+use crate::reader::Reader;
+
+pub const CIF_MAGIC: u32 = 65601;
+
+const LINE_SECTION: u8 = 1;
+const LINE_ENTRY: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CifLine {
+  /// `[name]` in the text form.
+  Section(String),
+  /// `key value`; the value is kept verbatim, quotes included.
+  Entry { key: String, value: String },
+  /// A line of a type this parser does not know, with its text.
+  Other(u8, String),
+}
+
+/// Header words without a known meaning, kept for writing the file back.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CifHeader {
+  pub unknown: u32,
+  pub index_unknown: u32,
+  pub text_unknown: u32,
+}
+
+/// How a file stored its lines, kept so that `write_cif` puts unchanged lines
+/// back where they were and the rest of the file survives byte for byte.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CifLayout {
+  /// Every u32 of the index block, entries past the line count included.
+  pub offsets: Vec<u32>,
+  /// Index block bytes after the last whole u32.
+  pub index_tail: Vec<u8>,
+  /// The decoded text block.
+  pub text: Vec<u8>,
+  /// The lines as read, to tell which ones changed.
+  pub lines: Vec<CifLine>,
+  /// Bytes after the text block.
+  pub trailing: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CifDocument {
+  pub header: CifHeader,
+  pub lines: Vec<CifLine>,
+  /// Empty for a document that was not read from a file.
+  pub layout: CifLayout,
+}
+
+/// Obfuscates one block in place, the inverse of `decode_block`.
+pub fn encode_block(data: &mut [u8]) {
+  let mut a = 71u8;
+  let mut b = 126u8;
+
+  for c in data.iter_mut() {
+    *c = (*c ^ a).wrapping_add(1);
+    a = a.wrapping_add(b);
+    b = b.wrapping_add(33);
+  }
+}
+
+/// Undoes the obfuscation of one block in place.
+pub fn decode_block(data: &mut [u8]) {
+  let mut a = 71u8;
+  let mut b = 126u8;
+
+  for c in data.iter_mut() {
+    *c = c.wrapping_sub(1) ^ a;
+    a = a.wrapping_add(b);
+    b = b.wrapping_add(33);
+  }
+}
+
+/// A block header and its decoded contents.
+fn read_block(r: &mut Reader) -> Result<(u32, Vec<u8>), &'static str> {
+  if r.u32()? != 1 {
+    return Err("CIF block tag is not 1.");
+  }
+  let size = r.u32()? as usize;
+  let unknown = r.u32()?;
+
+  let mut data = r.bytes(size)?.to_vec();
+  decode_block(&mut data);
+
+  Ok((unknown, data))
+}
+
+/// Text of an entry line. An empty value leaves out the separator.
+fn entry_text(key: &str, value: &str) -> String {
+  if value.is_empty() { key.to_string() } else { format!("{} {}", key, value) }
+}
+
+/// Entry lines only become `CifLine::Entry` when `entry_text` and the text
+/// form give them back unchanged; anything else stays verbatim in
+/// `CifLine::Other`.
+fn parse_line(raw: &[u8]) -> CifLine {
+  match raw.split_first() {
+    Some((&LINE_SECTION, name)) => CifLine::Section(codepage::decode(name)),
+    Some((&LINE_ENTRY, text)) => {
+      let text = codepage::decode(text);
+      let mut parts = text.splitn(2, ' ');
+      let key = parts.next().unwrap_or("").to_string();
+      let value = parts.next().unwrap_or("").to_string();
+
+      if key.is_empty() || key.starts_with(['[', '#']) || entry_text(&key, &value) != text {
+        return CifLine::Other(LINE_ENTRY, text);
+      }
+
+      CifLine::Entry { key, value }
+    },
+    Some((&t, text)) => CifLine::Other(t, codepage::decode(text)),
+    None => CifLine::Other(0, String::new()),
+  }
+}
+
+/// Parses a game configuration file (`.cif`), an obfuscated and indexed list
+/// of INI-like lines.
+///
+/// Layout, all integers u32 little endian:
+///
+/// ```text
+/// magic (65601), line count, unknown
+/// index block: tag (1), byte size, unknown, encoded u32 offset per line
+/// text block:  tag (1), byte size, unknown, encoded lines
+/// ```
+///
+/// Every line in the text block is a type byte followed by its text and a
+/// terminating 0. Type 1 opens a section, type 2 is a `key value` entry.
+/// Both blocks are encoded with `decode_block`. Offsets may be shared or out
+/// of order, the index may hold more entries than lines and bytes may follow
+/// the text block; all of it is kept in `CifDocument::layout`.
+pub fn read_cif(buf: &[u8]) -> Result<CifDocument, &'static str> {
+  let mut r = Reader::new(buf, "CIF file is truncated.");
+
+  if r.u32()? != CIF_MAGIC {
+    return Err("Not a CIF file.");
+  }
+  let count = r.u32()? as usize;
+  let unknown = r.u32()?;
+
+  let (index_unknown, index) = read_block(&mut r)?;
+  let (text_unknown, text) = read_block(&mut r)?;
+
+  if index.len() < 4 * count {
+    return Err("CIF index is shorter than the line count.");
+  }
+
+  let offsets: Vec<u32> = index.chunks_exact(4).map(|o| u32::from_le_bytes([o[0], o[1], o[2], o[3]])).collect();
+
+  let mut lines = Vec::with_capacity(count);
+  for &offset in offsets.iter().take(count) {
+    let rest = text.get(offset as usize..).ok_or("CIF line offset is past the text block.")?;
+    let end = rest.iter().position(|&c| c == 0).ok_or("CIF line is not terminated.")?;
+
+    lines.push(parse_line(&rest[..end]));
+  }
+
+  let layout = CifLayout {
+    index_tail: index[4 * offsets.len()..].to_vec(),
+    offsets,
+    text,
+    lines: lines.clone(),
+    trailing: r.rest().to_vec(),
+  };
+
+  Ok(CifDocument { header: CifHeader { unknown, index_unknown, text_unknown }, lines, layout })
+}
+
+impl CifDocument {
+  /// Plain INI-like text, one line per `CifLine`. Lines of unknown types are
+  /// written as `#<type> text`.
+  pub fn to_text(&self) -> String {
+    let mut out = String::new();
+
+    for line in self.lines.iter() {
+      match line {
+        CifLine::Section(name) => out.push_str(&format!("[{}]", name)),
+        CifLine::Entry { key, value } => out.push_str(&entry_text(key, value)),
+        CifLine::Other(t, text) => out.push_str(&format!("#{} {}", t, text)),
+      }
+      out.push('\n');
+    }
+
+    out
+  }
+
+  /// Parses the text form written by `to_text`. Blank lines are skipped, and
+  /// so are comments: lines starting with `#` that are not `#<type> text`.
+  /// The header and layout come from `original`, the document the text was
+  /// made from, or `CifDocument::default()` for a new file.
+  pub fn from_text(text: &str, original: &CifDocument) -> Result<CifDocument, &'static str> {
+    let mut lines = vec![];
+
+    for line in text.split('\n') {
+      let line = line.strip_suffix('\r').unwrap_or(line);
+      if line.trim().is_empty() {
+        continue;
+      }
+
+      if line.starts_with('[') && line.ends_with(']') && line.len() >= 2 {
+        lines.push(CifLine::Section(line[1..line.len() - 1].to_string()));
+      } else if let Some(rest) = line.strip_prefix('#') {
+        let mut parts = rest.splitn(2, ' ');
+        if let Ok(t) = parts.next().unwrap_or("").parse::<u8>() {
+          lines.push(CifLine::Other(t, parts.next().unwrap_or("").to_string()));
+        }
+      } else {
+        let mut parts = line.splitn(2, ' ');
+        let key = parts.next().unwrap_or("").to_string();
+        let value = parts.next().unwrap_or("").to_string();
+        lines.push(CifLine::Entry { key, value });
+      }
+    }
+
+    Ok(CifDocument { header: original.header, lines, layout: original.layout.clone() })
+  }
+
+  /// Sections in file order with their entries. Entries before the first
+  /// section are left out.
+  #[cfg_attr(not(feature = "unverified-formats"), allow(dead_code))]
+  pub fn sections(&self) -> Vec<(&str, Vec<(&str, &str)>)> {
+    let mut sections: Vec<(&str, Vec<(&str, &str)>)> = vec![];
+
+    for line in self.lines.iter() {
+      match line {
+        CifLine::Section(name) => sections.push((name, vec![])),
+        CifLine::Entry { key, value } => {
+          if let Some(section) = sections.last_mut() {
+            section.1.push((key, value));
+          }
+        },
+        CifLine::Other(..) => {},
+      }
+    }
+
+    sections
+  }
+}
+
+fn push_u32(out: &mut Vec<u8>, val: u32) {
+  out.extend_from_slice(&val.to_le_bytes());
+}
+
+/// Text in the game's code page, see `codepage`, without the 0 that ends a
+/// line.
+fn push_text(out: &mut Vec<u8>, text: &str) -> Result<(), &'static str> {
+  if text.contains('\0') {
+    return Err("CIF text must not contain 0.");
+  }
+
+  codepage::encode_into(out, text, "CIF text must be Windows-1252 characters.")
+}
+
+fn push_line(text: &mut Vec<u8>, line: &CifLine) -> Result<(), &'static str> {
+  match line {
+    CifLine::Section(name) => {
+      text.push(LINE_SECTION);
+      push_text(text, name)?;
+    },
+    CifLine::Entry { key, value } => {
+      text.push(LINE_ENTRY);
+      push_text(text, &entry_text(key, value))?;
+    },
+    CifLine::Other(t, line) => {
+      text.push(*t);
+      push_text(text, line)?;
+    },
+  }
+  text.push(0);
+
+  Ok(())
+}
+
+/// Encodes a document in the layout read by `read_cif`, over the layout of
+/// the file it was read from. A line equal to the one read at its position
+/// keeps its offset; other lines are appended to the text block. Index
+/// entries past the line count, text no line points at any more and bytes
+/// after the text block are written back unchanged, so an unmodified
+/// document gives back the file it was read from.
+pub fn write_cif(doc: &CifDocument) -> Result<Vec<u8>, &'static str> {
+  let layout = &doc.layout;
+  let mut text = layout.text.clone();
+  let mut offsets = Vec::with_capacity(doc.lines.len());
+
+  for (i, line) in doc.lines.iter().enumerate() {
+    match layout.lines.get(i) {
+      Some(read) if read == line => offsets.push(layout.offsets[i]),
+      _ => {
+        offsets.push(text.len() as u32);
+        push_line(&mut text, line)?;
+      },
+    }
+  }
+  offsets.extend_from_slice(&layout.offsets[layout.lines.len()..]);
+
+  let mut index = Vec::with_capacity(4 * offsets.len() + layout.index_tail.len());
+  for &offset in offsets.iter() {
+    push_u32(&mut index, offset);
+  }
+  index.extend_from_slice(&layout.index_tail);
+
+  let mut out = vec![];
+  push_u32(&mut out, CIF_MAGIC);
+  push_u32(&mut out, doc.lines.len() as u32);
+  push_u32(&mut out, doc.header.unknown);
+
+  for (unknown, block) in [(doc.header.index_unknown, index), (doc.header.text_unknown, text)].iter_mut() {
+    push_u32(&mut out, 1);
+    push_u32(&mut out, block.len() as u32);
+    push_u32(&mut out, *unknown);
+    encode_block(block);
+    out.extend_from_slice(block);
+  }
+  out.extend_from_slice(&layout.trailing);
+
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A file with `count` lines over the given index words and text block,
+  /// followed by `trailing`.
+  fn raw_cif(count: u32, offsets: &[u32], text: &[u8], trailing: &[u8]) -> Vec<u8> {
+    let index: Vec<u8> = offsets.iter().flat_map(|o| o.to_le_bytes().to_vec()).collect();
+
+    let mut out = vec![];
+    for word in [CIF_MAGIC, count, 7].iter() {
+      out.extend_from_slice(&word.to_le_bytes());
+    }
+    for block in [index, text.to_vec()].iter() {
+      for word in [1, block.len() as u32, 0].iter() {
+        out.extend_from_slice(&word.to_le_bytes());
+      }
+      let mut block = block.clone();
+      encode_block(&mut block);
+      out.extend_from_slice(&block);
+    }
+    out.extend_from_slice(trailing);
+    out
+  }
+
+  fn cif(lines: &[&[u8]]) -> Vec<u8> {
+    let mut offsets = vec![];
+    let mut text = vec![];
+    for line in lines {
+      offsets.push(text.len() as u32);
+      text.extend_from_slice(line);
+      text.push(0);
+    }
+
+    raw_cif(lines.len() as u32, &offsets, &text, &[])
+  }
+
+  #[test]
+  fn test_read_cif() {
+    let buf = cif(&[b"\x01GfxLandscape", b"\x02name \"ls_gras\"", b"\x02bmd data\\ls_gras.bmd", b"\x01GfxLandscape", b"\x02name \"ls_sand\""]);
+    let doc = read_cif(&buf).unwrap();
+
+    assert_eq!(doc.header.unknown, 7);
+    assert_eq!(doc.lines[1], CifLine::Entry { key: "name".to_string(), value: "\"ls_gras\"".to_string() });
+    assert_eq!(doc.to_text(), "[GfxLandscape]\nname \"ls_gras\"\nbmd data\\ls_gras.bmd\n[GfxLandscape]\nname \"ls_sand\"\n");
+
+    let sections = doc.sections();
+    assert_eq!(sections.len(), 2);
+    assert_eq!(sections[0].1, vec![("name", "\"ls_gras\""), ("bmd", "data\\ls_gras.bmd")]);
+  }
+
+  #[test]
+  fn test_read_cif_errors() {
+    let buf = cif(&[b"\x01Section"]);
+
+    assert!(read_cif(&buf[..buf.len() - 1]).is_err());
+    assert!(read_cif(&[0; 12]).is_err());
+  }
+
+  #[test]
+  fn test_cif_round_trip() {
+    let buf = cif(&[b"\x01Jobs", b"\x02job 3 \"farmer\" 12", b"\x02flag", b"\x02odd  spacing", b"\x02#comment", b"\x07raw \xe4"]);
+    let doc = read_cif(&buf).unwrap();
+
+    assert_eq!(write_cif(&doc).unwrap(), buf);
+
+    // The text form keeps every line, including the ones stored verbatim.
+    let text = doc.to_text();
+    assert_eq!(CifDocument::from_text(&text, &doc).unwrap(), doc);
+
+    let edited = text.replace("12", "15");
+    let edited = read_cif(&write_cif(&CifDocument::from_text(&edited, &doc).unwrap()).unwrap()).unwrap();
+    assert_eq!(edited.lines[1], CifLine::Entry { key: "job".to_string(), value: "3 \"farmer\" 15".to_string() });
+
+    let fresh = CifDocument::from_text(&text, &CifDocument { header: doc.header, ..CifDocument::default() }).unwrap();
+    assert_eq!(write_cif(&fresh).unwrap(), buf);
+
+    let commented = CifDocument::from_text("# jobs\n[Jobs]\n#note\n#7 raw\nflag\n", &doc).unwrap();
+    assert_eq!(commented.lines, vec![CifLine::Section("Jobs".to_string()), CifLine::Other(7, "raw".to_string()), CifLine::Entry { key: "flag".to_string(), value: String::new() }]);
+  }
+
+  #[test]
+  fn test_cif_keeps_layout() {
+    // Lines stored out of order, two entries sharing a line, an index entry
+    // past the line count, a stray byte after the last line and trailing data.
+    let text = b"\x02flag\0\x01Jobs\0\x02job 1\0\xff";
+    let buf = raw_cif(4, &[6, 12, 0, 0, 12], text, b"\x00tail");
+    let doc = read_cif(&buf).unwrap();
+
+    assert_eq!(doc.lines[0], CifLine::Section("Jobs".to_string()));
+    assert_eq!(doc.lines[2], doc.lines[3]);
+    assert_eq!(write_cif(&doc).unwrap(), buf);
+    assert_eq!(write_cif(&CifDocument::from_text(&doc.to_text(), &doc).unwrap()).unwrap(), buf);
+
+    // Editing one line appends it and leaves every other byte in place.
+    let mut edited = doc.clone();
+    edited.lines[1] = CifLine::Entry { key: "job".to_string(), value: "2".to_string() };
+    let out = write_cif(&edited).unwrap();
+    let reread = read_cif(&out).unwrap();
+
+    assert_eq!(reread.lines, edited.lines);
+    assert_eq!(reread.layout.offsets, vec![6, text.len() as u32, 0, 0, 12]);
+    assert_eq!(&reread.layout.text[..text.len()], &text[..]);
+    assert_eq!(reread.layout.trailing, b"\x00tail");
+
+    // Adding and dropping lines keeps the extra index entry after the lines.
+    edited.lines.push(CifLine::Section("More".to_string()));
+    assert_eq!(read_cif(&write_cif(&edited).unwrap()).unwrap().layout.offsets[5], 12);
+    edited.lines.truncate(1);
+    assert_eq!(read_cif(&write_cif(&edited).unwrap()).unwrap().layout.offsets, vec![6, 12]);
+  }
+}
@@ -0,0 +1,57 @@
+/// Windows-1252, the game's single byte code page. Bytes 0x80 to 0x9F are
+/// typographic characters there; the five bytes it leaves undefined map to
+/// the C1 control with the same value so every byte survives a round trip.
+/// All other bytes match the first 256 Unicode characters.
+const HIGH: [char; 32] = [
+  '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+  '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+  '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+  '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+pub fn decode_byte(b: u8) -> char {
+  match b {
+    0x80..=0x9F => HIGH[(b - 0x80) as usize],
+    _ => b as char,
+  }
+}
+
+/// The byte for `c`, if the code page has one.
+pub fn encode_char(c: char) -> Option<u8> {
+  match c as u32 {
+    0x80..=0x9F => HIGH.iter().position(|&h| h == c).map(|i| 0x80 + i as u8),
+    0..=0xFF => Some(c as u8),
+    _ => HIGH.iter().position(|&h| h == c).map(|i| 0x80 + i as u8),
+  }
+}
+
+pub fn decode(bytes: &[u8]) -> String {
+  bytes.iter().map(|&b| decode_byte(b)).collect()
+}
+
+/// Appends `text` encoded, failing with `err` on a character the code page
+/// lacks.
+pub fn encode_into(out: &mut Vec<u8>, text: &str, err: &'static str) -> Result<(), &'static str> {
+  for c in text.chars() {
+    out.push(encode_char(c).ok_or(err)?);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_codepage_round_trip() {
+    for b in 0..=255u8 {
+      assert_eq!(encode_char(decode_byte(b)), Some(b));
+    }
+
+    assert_eq!(decode(b"\x80 \x9C\xE4"), "\u{20AC} \u{0153}\u{e4}");
+    assert_eq!(encode_char('\u{2122}'), Some(0x99));
+    assert_eq!(encode_char('\u{0080}'), None);
+    assert_eq!(encode_char('\u{3042}'), None);
+  }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::codepage;
+use crate::pcx::{self, MaskAlpha};
+use crate::reader::Reader;
+
+/// One character of a font. `pixels` holds `width * height` palette indices,
+/// 0 being transparent; the offsets place it relative to the pen position at
+/// the top of its line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glyph {
+  pub code: u32,
+  pub x_offset: i32,
+  pub y_offset: i32,
+  pub advance: i32,
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u8>,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Font {
+  line_height: i32,
+  baseline: i32,
+  glyphs: Vec<Glyph>,
+  lookup: HashMap<u32, usize>,
+  kerning: HashMap<(u32, u32), i32>,
+  palette: Vec<u8>,
+}
+
+/// A glyph placed by `layout_text`, in pixels from the top left of the text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlyphQuad {
+  /// Index into `Font::glyphs`.
+  pub glyph: usize,
+  pub x: i32,
+  pub y: i32,
+  pub width: usize,
+  pub height: usize,
+}
+
+/// Parses a bitmap font file.
+///
+/// Layout, all integers 32 bit little endian:
+///
+/// ```text
+/// line height, baseline, glyph count, kerning pair count
+/// per glyph:   code, x offset, y offset, advance, width, height, pixel offset
+/// per pair:    left code, right code, advance adjustment
+/// pixel size, then the palette indices of all glyphs
+/// 0x0C and 768 bytes of RGB palette, as at the end of a PCX file
+/// ```
+///
+/// Codes are bytes of the game's code page, Windows-1252; see `codepage`
+/// for how they map to characters.
+pub fn read_font(buf: &[u8]) -> Result<Font, &'static str> {
+  let mut r = Reader::new(buf, "Font file is truncated.");
+
+  let line_height = r.i32()?;
+  let baseline = r.i32()?;
+  let glyph_count = r.u32()? as usize;
+  let kerning_count = r.u32()? as usize;
+
+  let mut metrics = Vec::with_capacity(glyph_count.min(buf.len()));
+  for _ in 0..glyph_count {
+    metrics.push((r.u32()?, r.i32()?, r.i32()?, r.i32()?, r.u32()? as usize, r.u32()? as usize, r.u32()? as usize));
+  }
+
+  let mut kerning = HashMap::new();
+  for _ in 0..kerning_count {
+    kerning.insert((r.u32()?, r.u32()?), r.i32()?);
+  }
+
+  let size = r.u32()? as usize;
+  let pixels = r.bytes(size)?;
+  let palette = pcx::read_palette(r.rest()).map_err(|_| "Font file has no palette.")?.to_vec();
+
+  let mut glyphs = Vec::with_capacity(metrics.len());
+  let mut lookup = HashMap::new();
+  for (code, x_offset, y_offset, advance, width, height, offset) in metrics {
+    let len = width.checked_mul(height).ok_or("Font glyph is too large.")?;
+    let data = pixels.get(offset..offset.saturating_add(len)).ok_or("Font glyph points past the pixel data.")?;
+
+    lookup.entry(code).or_insert(glyphs.len());
+    glyphs.push(Glyph { code, x_offset, y_offset, advance, width, height, pixels: data.to_vec() });
+  }
+
+  Ok(Font { line_height, baseline, glyphs, lookup, kerning, palette })
+}
+
+impl Font {
+  pub fn line_height(&self) -> i32 {
+    self.line_height
+  }
+
+  pub fn baseline(&self) -> i32 {
+    self.baseline
+  }
+
+  pub fn glyphs(&self) -> &[Glyph] {
+    &self.glyphs
+  }
+
+  /// Index of the glyph drawn for `c`, looked up by its code page byte and
+  /// falling back to `?`.
+  pub fn glyph_index(&self, c: char) -> Option<usize> {
+    codepage::encode_char(c).and_then(|b| self.lookup.get(&(b as u32)))
+      .or_else(|| self.lookup.get(&('?' as u32)))
+      .copied()
+  }
+
+  pub fn kerning(&self, left: u32, right: u32) -> i32 {
+    self.kerning.get(&(left, right)).copied().unwrap_or(0)
+  }
+
+  /// RGBA pixels of a glyph, transparent where the index is 0.
+  pub fn glyph_rgba(&self, glyph: &Glyph) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 * glyph.pixels.len());
+    for &p in glyph.pixels.iter() {
+      let i = 3 * p as usize;
+      out.extend_from_slice(&self.palette[i..i + 3]);
+      out.push(if p == 0 { 0 } else { 0xFF });
+    }
+    out
+  }
+
+  /// One alpha byte per glyph pixel from its palette colour, see
+  /// `pcx::MaskAlpha`. Index 0 stays transparent.
+  pub fn glyph_alpha(&self, glyph: &Glyph, mask_alpha: &MaskAlpha) -> Vec<u8> {
+    glyph.pixels.iter().map(|&p| if p == 0 { 0 } else { mask_alpha.alpha(p, &self.palette) }).collect()
+  }
+}
+
+/// Width of a run of glyphs including the kerning between them.
+fn run_width(font: &Font, run: &[usize]) -> i32 {
+  run.iter().enumerate().map(|(i, &g)| {
+    let kern = if i > 0 { font.kerning(font.glyphs[run[i - 1]].code, font.glyphs[g].code) } else { 0 };
+    font.glyphs[g].advance + kern
+  }).sum()
+}
+
+/// Places the glyphs of `text` line by line. Lines break at `\n` and, when
+/// `max_width` is not 0, before words that would cross it; words wider than a
+/// line are split between characters. Characters without a glyph and without
+/// a `?` fallback are skipped.
+pub fn layout_text(text: &str, font: &Font, max_width: usize) -> Vec<GlyphQuad> {
+  let max_width = if max_width == 0 { i32::MAX } else { max_width.min(i32::MAX as usize) as i32 };
+  let space = font.glyph_index(' ');
+
+  let mut quads = vec![];
+  let mut y = 0;
+
+  for line in text.split('\n') {
+    let mut pen = 0;
+    let mut prev: Option<usize> = None;
+
+    for (i, word) in line.split(' ').enumerate() {
+      let run: Vec<usize> = word.chars().filter_map(|c| font.glyph_index(c)).collect();
+
+      if i > 0 {
+        let space_advance = space.map_or(0, |s| font.glyphs[s].advance + prev.map_or(0, |p| font.kerning(font.glyphs[p].code, font.glyphs[s].code)));
+
+        if pen > 0 && pen + space_advance + run_width(font, &run) > max_width {
+          pen = 0;
+          prev = None;
+          y += font.line_height;
+        } else {
+          pen += space_advance;
+          prev = space;
+        }
+      }
+
+      for g in run {
+        let glyph = &font.glyphs[g];
+        let kern = prev.map_or(0, |p| font.kerning(font.glyphs[p].code, glyph.code));
+
+        if pen > 0 && pen + kern + glyph.advance > max_width {
+          pen = 0;
+          y += font.line_height;
+        } else {
+          pen += kern;
+        }
+
+        if glyph.width > 0 && glyph.height > 0 {
+          quads.push(GlyphQuad { glyph: g, x: pen + glyph.x_offset, y: y + glyph.y_offset, width: glyph.width, height: glyph.height });
+        }
+
+        pen += glyph.advance;
+        prev = Some(g);
+      }
+    }
+
+    y += font.line_height;
+  }
+
+  quads
+}
+
+#[wasm_bindgen]
+impl Font {
+  #[wasm_bindgen(js_name = read)]
+  pub fn js_read(buf: &[u8]) -> Result<Font, JsValue> {
+    read_font(buf).map_err(|err| JsValue::from_str(&format!("Font.read: {}", err)))
+  }
+
+  #[wasm_bindgen(getter, js_name = lineHeight)]
+  pub fn js_line_height(&self) -> i32 {
+    self.line_height
+  }
+
+  #[wasm_bindgen(getter, js_name = baseline)]
+  pub fn js_baseline(&self) -> i32 {
+    self.baseline
+  }
+
+  /// `(code, x offset, y offset, advance, width, height)` per glyph.
+  #[wasm_bindgen(js_name = glyphMetrics)]
+  pub fn js_glyph_metrics(&self) -> Box<[i32]> {
+    self.glyphs.iter()
+      .flat_map(|g| vec![g.code as i32, g.x_offset, g.y_offset, g.advance, g.width as i32, g.height as i32])
+      .collect::<Vec<_>>()
+      .into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = glyphRgba)]
+  pub fn js_glyph_rgba(&self, glyph: usize) -> Option<Box<[u8]>> {
+    self.glyphs.get(glyph).map(|g| self.glyph_rgba(g).into_boxed_slice())
+  }
+
+  /// `mask_channel` and `mask_threshold` as for `create_2d_texture_masked`;
+  /// an unknown channel is an error.
+  #[wasm_bindgen(js_name = glyphAlpha)]
+  pub fn js_glyph_alpha(&self, glyph: usize, mask_channel: Option<u8>, mask_threshold: Option<u8>) -> Result<Option<Box<[u8]>>, JsValue> {
+    let mask_alpha = crate::mask_alpha(mask_channel, mask_threshold, "Font.glyphAlpha")?;
+
+    Ok(self.glyphs.get(glyph).map(|g| self.glyph_alpha(g, &mask_alpha).into_boxed_slice()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A font with glyphs one pixel narrower than their advance, filled with
+  /// the low bits of their code, and `A` and `V` kerned together.
+  fn font(glyphs: &[(char, i32)]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut pixels = vec![];
+    let push = |out: &mut Vec<u8>, v: i32| out.extend_from_slice(&v.to_le_bytes());
+
+    for &v in [12, 9, glyphs.len() as i32, 1].iter() {
+      push(&mut out, v);
+    }
+    for &(c, advance) in glyphs {
+      let (w, h) = if c == ' ' { (0, 0) } else { (advance - 1, 2) };
+      for &v in [codepage::encode_char(c).unwrap() as i32, 0, 1, advance, w, h, pixels.len() as i32].iter() {
+        push(&mut out, v);
+      }
+      pixels.extend(std::iter::repeat_n(c as u8 & 3, (w * h) as usize));
+    }
+    for &v in ['A' as i32, 'V' as i32, -2].iter() {
+      push(&mut out, v);
+    }
+    push(&mut out, pixels.len() as i32);
+    out.extend_from_slice(&pixels);
+
+    out.push(0x0C);
+    out.extend((0..768).map(|i| if i < 3 { 0 } else { 0xFF }));
+    out
+  }
+
+  #[test]
+  fn test_read_font() {
+    let f = read_font(&font(&[('A', 6), ('V', 6), (' ', 3)])).unwrap();
+
+    assert_eq!((f.line_height(), f.baseline(), f.glyphs().len()), (12, 9, 3));
+    let a = &f.glyphs()[f.glyph_index('A').unwrap()];
+    assert_eq!((a.width, a.height, a.pixels[0]), (5, 2, 1));
+    assert_eq!(f.glyph_index('Z'), None);
+    assert_eq!(f.kerning('A' as u32, 'V' as u32), -2);
+
+    let euro = read_font(&font(&[('\u{20AC}', 6)])).unwrap();
+    assert_eq!(euro.glyphs()[0].code, 0x80);
+    assert_eq!(euro.glyph_index('\u{20AC}'), Some(0));
+    assert_eq!(euro.glyph_index('\u{80}'), None);
+
+    assert_eq!(&f.glyph_rgba(a)[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    let luminance = MaskAlpha { channel: pcx::MaskChannel::Luminance, threshold: None };
+    assert_eq!(f.glyph_alpha(a, &luminance)[0], 0xFF);
+
+    let buf = font(&[('A', 6)]);
+    assert!(read_font(&buf[..buf.len() - 1]).is_err());
+  }
+
+  #[test]
+  fn test_layout_text() {
+    let f = read_font(&font(&[('A', 6), ('V', 6), ('?', 4), (' ', 3)])).unwrap();
+    let pos = |quads: &[GlyphQuad]| quads.iter().map(|q| (q.x, q.y)).collect::<Vec<_>>();
+
+    // Kerning pulls V towards A, the space has no quad and Z falls back to ?.
+    assert_eq!(pos(&layout_text("AV Z", &f, 0)), vec![(0, 1), (4, 1), (13, 1)]);
+
+    // "AV AV" is 25 wide: the second word moves to the next line.
+    assert_eq!(pos(&layout_text("AV AV", &f, 20)), vec![(0, 1), (4, 1), (0, 13), (4, 13)]);
+    assert_eq!(layout_text("AV AV", &f, 25).len(), 4);
+    assert_eq!(layout_text("AV AV", &f, 25)[3].y, 1);
+
+    // A word wider than the line is split; newlines always break.
+    assert_eq!(pos(&layout_text("AAA\nA", &f, 13)), vec![(0, 1), (6, 1), (0, 13), (0, 25)]);
+  }
+}
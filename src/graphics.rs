@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use crate::cif::CifDocument;
+use crate::landscape::{unquote, words};
+use crate::vfs::normalize_path;
+
+/// A drawable object from the graphics definitions:
+///
+/// ```text
+/// [GfxPalette]
+/// name "tree01"
+/// path "data\engine2d\bin\palettes\landscapes\tree01.pcx"
+///
+/// [GfxObject]
+/// name "ls_tree01"
+/// bmd "data\engine2d\bin\bobs\ls_trees.bmd"
+/// shadow "data\engine2d\bin\bobs\ls_trees_s.bmd"
+/// palette "tree01"
+/// frames 0-3 8
+/// ```
+///
+/// `[GfxLandscape]` sections are read like `[GfxObject]`. `frames` may repeat
+/// and takes frame numbers and inclusive ranges. Keys are case-insensitive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GfxObject {
+  pub name: String,
+  pub bmd: String,
+  pub shadow: Option<String>,
+  pub palette: String,
+  pub frames: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GraphicsDefinitions {
+  /// `(name, path)` of every palette PCX.
+  pub palettes: Vec<(String, String)>,
+  pub objects: Vec<GfxObject>,
+}
+
+/// Where an object's frames ended up: block `bmd` of the texture array,
+/// instances `first..first + count` within it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GfxLayers {
+  pub bmd: usize,
+  pub first: usize,
+  pub count: usize,
+}
+
+/// The arguments of `create_bmd_texture_array_from_vfs` for a set of objects,
+/// with one block per distinct BMD and shadow pair.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GfxPlan {
+  pub bmd_paths: Vec<String>,
+  pub shadow_paths: Vec<Option<String>>,
+  pub palette_paths: Vec<String>,
+  pub bmd_frame_instance_count: Vec<usize>,
+  /// Starts with one skipped entry per block, as `create_bmd_texture_array`
+  /// expects, followed by `(frame, palette)` pairs.
+  pub frame_palette_index: Vec<usize>,
+  pub lookup: Vec<(String, GfxLayers)>,
+}
+
+fn parse_frames(value: &str, out: &mut Vec<usize>) -> Result<(), &'static str> {
+  for word in words(value) {
+    let mut bounds = word.splitn(2, '-').map(|b| b.parse::<usize>().map_err(|_| "Graphics frame is not a number."));
+    let first = bounds.next().unwrap_or(Err("Graphics frame is empty."))?;
+    let last = bounds.next().unwrap_or(Ok(first))?;
+
+    if last < first {
+      return Err("Graphics frame range ends before it starts.");
+    }
+    out.extend(first..=last);
+  }
+
+  Ok(())
+}
+
+fn parse_object(entries: &[(&str, &str)]) -> Result<GfxObject, &'static str> {
+  let mut object = GfxObject { name: String::new(), bmd: String::new(), shadow: None, palette: String::new(), frames: vec![] };
+
+  for (key, value) in entries {
+    match key.to_ascii_lowercase().as_str() {
+      "name" => object.name = unquote(value).to_string(),
+      "bmd" => object.bmd = unquote(value).to_string(),
+      "shadow" => object.shadow = Some(unquote(value).to_string()).filter(|s| !s.is_empty()),
+      "palette" => object.palette = unquote(value).to_string(),
+      "frames" => parse_frames(value, &mut object.frames)?,
+      _ => {},
+    }
+  }
+
+  if object.name.is_empty() || object.bmd.is_empty() || object.palette.is_empty() {
+    return Err("Graphics object needs a name, bmd and palette.");
+  }
+  if object.frames.is_empty() {
+    return Err("Graphics object without frames.");
+  }
+
+  Ok(object)
+}
+
+fn parse_palette(entries: &[(&str, &str)]) -> Result<(String, String), &'static str> {
+  let find = |k: &str| entries.iter().rev().find(|(key, _)| key.eq_ignore_ascii_case(k)).map(|(_, v)| unquote(v).to_string());
+
+  match (find("name"), find("path")) {
+    (Some(name), Some(path)) if !name.is_empty() && !path.is_empty() => Ok((name, path)),
+    _ => Err("Graphics palette needs a name and path."),
+  }
+}
+
+/// Palettes and objects of a decoded graphics definition file, in file order.
+pub fn parse_graphics(doc: &CifDocument) -> Result<GraphicsDefinitions, String> {
+  let mut defs = GraphicsDefinitions::default();
+
+  for (i, (name, entries)) in doc.sections().iter().enumerate() {
+    let section = name.to_ascii_lowercase();
+    let parsed = match section.as_str() {
+      "gfxpalette" => parse_palette(entries).map(|p| defs.palettes.push(p)),
+      "gfxobject" | "gfxlandscape" => parse_object(entries).map(|o| defs.objects.push(o)),
+      _ => Ok(()),
+    };
+
+    parsed.map_err(|err| format!("section #{} [{}]: {}", i, name, err))?;
+  }
+
+  Ok(defs)
+}
+
+impl GraphicsDefinitions {
+  pub fn object(&self, name: &str) -> Option<&GfxObject> {
+    self.objects.iter().find(|o| o.name.eq_ignore_ascii_case(name))
+  }
+
+  fn palette_path(&self, name: &str) -> Option<&str> {
+    self.palettes.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, p)| p.as_str())
+  }
+
+  /// Lays out the texture array for `names`. Objects sharing a BMD and shadow
+  /// share a block and palettes are loaded once.
+  pub fn plan(&self, names: &[&str]) -> Result<GfxPlan, String> {
+    let mut plan = GfxPlan::default();
+    let mut blocks: Vec<Vec<(usize, usize)>> = vec![];
+    let mut objects = Vec::with_capacity(names.len());
+    let mut palettes: HashMap<String, usize> = HashMap::new();
+    let mut bmds: HashMap<(String, Option<String>), usize> = HashMap::new();
+
+    for name in names {
+      let object = self.object(name).ok_or_else(|| format!("Unknown graphics object: {}", name))?;
+      let palette = self.palette_path(&object.palette).ok_or_else(|| format!("Unknown palette {} of {}", object.palette, name))?;
+
+      let palette = *palettes.entry(normalize_path(palette)).or_insert_with(|| {
+        plan.palette_paths.push(palette.to_string());
+        plan.palette_paths.len() - 1
+      });
+
+      let key = (normalize_path(&object.bmd), object.shadow.as_deref().map(normalize_path));
+      let bmd = *bmds.entry(key).or_insert_with(|| {
+        plan.bmd_paths.push(object.bmd.clone());
+        plan.shadow_paths.push(object.shadow.clone());
+        blocks.push(vec![]);
+        blocks.len() - 1
+      });
+
+      let first = blocks[bmd].len();
+      blocks[bmd].extend(object.frames.iter().map(|&f| (f, palette)));
+      objects.push((object.name.clone(), GfxLayers { bmd, first, count: object.frames.len() }));
+    }
+
+    plan.bmd_frame_instance_count = blocks.iter().map(|b| b.len()).collect();
+    plan.frame_palette_index = plan.bmd_frame_instance_count.clone();
+    for (frame, palette) in blocks.into_iter().flatten() {
+      plan.frame_palette_index.extend_from_slice(&[frame, palette]);
+    }
+    plan.lookup = objects;
+
+    Ok(plan)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DEFINITIONS: &str = "[GfxPalette]\nname \"tree01\"\npath \"data\\tree01.pcx\"\n\
+    [GfxPalette]\nname \"rock\"\npath \"data\\rock.pcx\"\n\
+    [GfxObject]\nname \"ls_tree01\"\nbmd \"data\\ls_trees.bmd\"\nshadow \"data\\ls_trees_s.bmd\"\npalette \"tree01\"\nframes 0-2 5\n\
+    [GfxLandscape]\nname \"ls_rock\"\nbmd \"data\\ls_rocks.bmd\"\npalette \"rock\"\nframes 4\n\
+    [GfxObject]\nname \"ls_tree02\"\nbmd \"DATA\\LS_TREES.BMD\"\nshadow \"data\\ls_trees_s.bmd\"\npalette \"Tree01\"\nframes 3\nframes 7\n";
+
+  fn definitions() -> GraphicsDefinitions {
+    parse_graphics(&CifDocument::from_text(DEFINITIONS, &Default::default()).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_parse_graphics() {
+    let defs = definitions();
+
+    assert_eq!(defs.palettes.len(), 2);
+    assert_eq!(defs.objects.len(), 3);
+    assert_eq!(defs.object("LS_TREE01").unwrap().frames, vec![0, 1, 2, 5]);
+    assert_eq!(defs.object("ls_rock").unwrap().shadow, None);
+    assert_eq!(defs.object("ls_tree02").unwrap().frames, vec![3, 7]);
+
+    for broken in ["[GfxObject]\nname \"x\"\nbmd \"x.bmd\"\npalette \"p\"\nframes 3-1\n", "[GfxObject]\nname \"x\"\n", "[GfxPalette]\nname \"p\"\n"].iter() {
+      assert!(parse_graphics(&CifDocument::from_text(broken, &Default::default()).unwrap()).is_err());
+    }
+  }
+
+  #[test]
+  fn test_graphics_plan() {
+    let plan = definitions().plan(&["ls_tree01", "ls_rock", "ls_tree02"]).unwrap();
+
+    assert_eq!(plan.bmd_paths, vec!["data\\ls_trees.bmd", "data\\ls_rocks.bmd"]);
+    assert_eq!(plan.shadow_paths, vec![Some("data\\ls_trees_s.bmd".to_string()), None]);
+    assert_eq!(plan.palette_paths, vec!["data\\tree01.pcx", "data\\rock.pcx"]);
+    assert_eq!(plan.bmd_frame_instance_count, vec![6, 1]);
+    assert_eq!(plan.frame_palette_index, vec![6, 1, 0, 0, 1, 0, 2, 0, 5, 0, 3, 0, 7, 0, 4, 1]);
+    assert_eq!(plan.lookup[2], ("ls_tree02".to_string(), GfxLayers { bmd: 0, first: 4, count: 2 }));
+
+    assert!(definitions().plan(&["missing"]).is_err());
+  }
+}
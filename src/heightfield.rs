@@ -0,0 +1,238 @@
+use wasm_bindgen::prelude::*;
+
+use crate::tessellate::{self, TessellationOptions};
+
+/// Terrain height queries over the same staggered layout and triangles that
+/// `tessellate::triangulate_map` renders. Positions are ground coordinates of
+/// the mesh, i.e. before elevation lifts vertices; heights are in the units of
+/// `TessellationOptions::height_scale`.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct Heightfield {
+  width: usize,
+  height: usize,
+  elevation: Vec<u8>,
+  options: TessellationOptions,
+}
+
+/// Ground corners and heights of one of the two triangles of a cell.
+type Triangle = [(f32, f32, f32); 3];
+
+impl Heightfield {
+  pub fn from_map(width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) -> Result<Heightfield, &'static str> {
+    if width == 0 || height == 0 {
+      return Err("Heightfield: map must not be empty.");
+    }
+    if elevation.len() < width * height {
+      return Err("Heightfield: elevation is smaller than the map.");
+    }
+
+    Ok(Heightfield { width, height, elevation: elevation.to_vec(), options: *options })
+  }
+
+  pub fn width(&self) -> usize {
+    self.width
+  }
+
+  pub fn height(&self) -> usize {
+    self.height
+  }
+
+  pub fn options(&self) -> &TessellationOptions {
+    &self.options
+  }
+
+  /// Height of elevation sample `(sx, sy)`; outside the map the border mode
+  /// decides.
+  pub fn sample(&self, sx: isize, sy: isize) -> f32 {
+    tessellate::sample_height(sx, sy, self.width, self.height, &self.elevation, &self.options)
+  }
+
+  /// Heights of the vertices of the indexed mesh, see
+  /// `tessellate::triangulate_map_vertices`.
+  pub fn vertex_heights(&self) -> Vec<f32> {
+    tessellate::vertex_heights(self.width, self.height, &self.elevation, &self.options)
+  }
+
+  /// Ground position of elevation sample `(sx, sy)`.
+  pub fn sample_position(&self, sx: isize, sy: isize) -> (f32, f32) {
+    let cs = self.options.cell_size;
+    (cs * (2.0 * sx as f32 + sy.rem_euclid(2) as f32), cs * 2.0 * sy as f32)
+  }
+
+  /// Triangle `t` (0 or 1, in `triangulate_map` order) of cell `i`.
+  pub fn triangle(&self, i: usize, t: usize) -> Triangle {
+    let cs = self.options.cell_size;
+    let x = i % self.width;
+    let y = i / self.width;
+    let off = (y % 2) as f32;
+    let fx = 2.0 * x as f32 + off;
+    let fy = 2.0 * y as f32;
+    let elv = tessellate::elevation_at(i, self.width, self.height, &self.elevation, &self.options);
+
+    if t == 0 {
+      [(cs * fx, cs * fy, elv[0]), (cs * (fx + 1.0), cs * (fy + 2.0), elv[1]), (cs * (fx - 1.0), cs * (fy + 2.0), elv[2])]
+    } else {
+      [(cs * fx, cs * fy, elv[0]), (cs * (fx + 2.0), cs * fy, elv[3]), (cs * (fx + 1.0), cs * (fy + 2.0), elv[1])]
+    }
+  }
+
+  /// Cell, triangle and barycentric weights of the triangle containing ground
+  /// position `(x, y)`.
+  pub fn locate(&self, x: f32, y: f32) -> Option<(usize, usize, [f32; 3])> {
+    const EPSILON: f32 = -1e-5;
+
+    let cs = self.options.cell_size;
+    let row = (y / cs / 2.0).floor();
+    if row < 0.0 || row >= self.height as f32 {
+      return None;
+    }
+
+    let row = row as usize;
+    let col = ((x / cs - (row % 2) as f32) / 2.0).floor() as isize;
+
+    for c in col - 1..=col + 1 {
+      if c < 0 || c >= self.width as isize {
+        continue;
+      }
+
+      let i = row * self.width + c as usize;
+      for t in 0..2 {
+        let tri = self.triangle(i, t);
+        let b = tessellate::barycentric((x, y), (tri[0].0, tri[0].1), (tri[1].0, tri[1].1), (tri[2].0, tri[2].1));
+
+        if let Some(b) = b {
+          if b.iter().all(|w| *w >= EPSILON) {
+            return Some((i, t, b));
+          }
+        }
+      }
+    }
+
+    None
+  }
+
+  /// Height of the rendered surface at `(x, y)`, interpolated over the
+  /// containing triangle.
+  pub fn height_at(&self, x: f32, y: f32) -> Option<f32> {
+    self.locate(x, y).map(|(i, t, b)| {
+      let tri = self.triangle(i, t);
+      b[0] * tri[0].2 + b[1] * tri[1].2 + b[2] * tri[2].2
+    })
+  }
+
+  /// Smooth height at `(x, y)` from the four nearest samples, interpolating
+  /// along each row first since odd rows are shifted by half a cell. Ignores
+  /// the flattened border of `BorderMode::Zero`.
+  pub fn height_bilinear(&self, x: f32, y: f32) -> f32 {
+    let cs = self.options.cell_size;
+    let fy = y / cs / 2.0;
+    let r0 = fy.floor();
+    let ty = fy - r0;
+
+    let row_height = |r: isize| {
+      let fx = (x / cs - r.rem_euclid(2) as f32) / 2.0;
+      let c0 = fx.floor();
+      let tx = fx - c0;
+      let c0 = c0 as isize;
+
+      self.sample(c0, r) * (1.0 - tx) + self.sample(c0 + 1, r) * tx
+    };
+
+    let r0 = r0 as isize;
+    row_height(r0) * (1.0 - ty) + row_height(r0 + 1) * ty
+  }
+
+  /// `(dh/dx, dh/dy)` of the triangle containing `(x, y)`.
+  pub fn gradient(&self, x: f32, y: f32) -> Option<[f32; 2]> {
+    self.locate(x, y).map(|(i, t, _)| triangle_gradient(&self.triangle(i, t)))
+  }
+
+  /// Angle of the surface at `(x, y)` against the horizontal, in radians.
+  pub fn slope(&self, x: f32, y: f32) -> Option<f32> {
+    self.gradient(x, y).map(slope_angle)
+  }
+
+  /// Steepest slope of the two triangles of every cell, in radians.
+  pub fn max_slope_map(&self) -> Vec<f32> {
+    (0..self.width * self.height).map(|i| {
+      let a = slope_angle(triangle_gradient(&self.triangle(i, 0)));
+      let b = slope_angle(triangle_gradient(&self.triangle(i, 1)));
+      a.max(b)
+    }).collect()
+  }
+}
+
+fn triangle_gradient(tri: &Triangle) -> [f32; 2] {
+  let (dx1, dy1, dh1) = (tri[1].0 - tri[0].0, tri[1].1 - tri[0].1, tri[1].2 - tri[0].2);
+  let (dx2, dy2, dh2) = (tri[2].0 - tri[0].0, tri[2].1 - tri[0].1, tri[2].2 - tri[0].2);
+
+  let det = dx1 * dy2 - dx2 * dy1;
+  if det.abs() < 1e-9 {
+    return [0.0, 0.0];
+  }
+
+  [(dh1 * dy2 - dh2 * dy1) / det, (dx1 * dh2 - dx2 * dh1) / det]
+}
+
+fn slope_angle(g: [f32; 2]) -> f32 {
+  (g[0] * g[0] + g[1] * g[1]).sqrt().atan()
+}
+
+#[wasm_bindgen]
+impl Heightfield {
+  #[wasm_bindgen(constructor)]
+  pub fn new(width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) -> Result<Heightfield, JsValue> {
+    Heightfield::from_map(width, height, elevation, options).map_err(JsValue::from_str)
+  }
+
+  #[wasm_bindgen(js_name = heightAt)]
+  pub fn js_height_at(&self, x: f32, y: f32) -> Option<f32> {
+    self.height_at(x, y)
+  }
+
+  #[wasm_bindgen(js_name = heightBilinear)]
+  pub fn js_height_bilinear(&self, x: f32, y: f32) -> f32 {
+    self.height_bilinear(x, y)
+  }
+
+  #[wasm_bindgen(js_name = gradient)]
+  pub fn js_gradient(&self, x: f32, y: f32) -> Option<Box<[f32]>> {
+    self.gradient(x, y).map(|g| vec![g[0], g[1]].into_boxed_slice())
+  }
+
+  #[wasm_bindgen(js_name = slope)]
+  pub fn js_slope(&self, x: f32, y: f32) -> Option<f32> {
+    self.slope(x, y)
+  }
+
+  #[wasm_bindgen(js_name = maxSlopeMap)]
+  pub fn js_max_slope_map(&self) -> Box<[f32]> {
+    self.max_slope_map().into_boxed_slice()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tessellate::BorderMode;
+
+  #[test]
+  fn test_heightfield_on_a_ramp() {
+    let (w, h) = (6, 6);
+    let elevation: Vec<u8> = (0..w * h).map(|i| (16 * (i / w)) as u8).collect();
+    let options = TessellationOptions { border_mode: BorderMode::Clamp, ..TessellationOptions::default() };
+    let field = Heightfield::from_map(w, h, &elevation, &options).unwrap();
+
+    // Height rises by one unit per sample row, i.e. every 2 ground units.
+    assert!((field.height_at(5.3, 4.6).unwrap() - 2.3).abs() < 1e-5);
+    assert!((field.height_bilinear(5.3, 4.6) - 2.3).abs() < 1e-5);
+
+    let g = field.gradient(5.3, 4.6).unwrap();
+    assert!(g[0].abs() < 1e-5 && (g[1] - 0.5).abs() < 1e-5);
+    assert!((field.slope(5.3, 4.6).unwrap() - 0.5f32.atan()).abs() < 1e-5);
+
+    assert_eq!(field.height_at(5.0, -1.0), None);
+    assert_eq!(field.max_slope_map().len(), w * h);
+  }
+}
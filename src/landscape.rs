@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::cif::CifDocument;
+use crate::vfs::Vfs;
+
+/// A terrain type from the landscape definitions, one `[LandscapeType]`
+/// section each:
+///
+/// ```text
+/// [LandscapeType]
+/// id 3
+/// name "sand"
+/// texture "data\engine2d\bin\textures\sand.pcx"
+/// transition 1 "data\engine2d\bin\textures\mask_sand_gras.pcx"
+/// walkable 1
+/// buildable 0
+/// resource "stone"
+/// ```
+///
+/// Keys are case-insensitive and unknown keys are ignored. `transition` may
+/// repeat and names the mask used where this type is drawn over type `1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LandscapeType {
+  pub id: u8,
+  pub name: String,
+  pub texture: String,
+  pub transitions: Vec<(u8, String)>,
+  pub walkable: bool,
+  pub buildable: bool,
+  pub resources: Vec<String>,
+}
+
+const SECTION: &str = "landscapetype";
+
+/// Strips the quotes around a string value.
+pub fn unquote(value: &str) -> &str {
+  let value = value.trim();
+  if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') { &value[1..value.len() - 1] } else { value }
+}
+
+/// Splits a value into whitespace separated words, keeping quoted strings
+/// together and unquoting them.
+pub fn words(value: &str) -> Vec<&str> {
+  let mut out = vec![];
+  let mut rest = value.trim_start();
+
+  while !rest.is_empty() {
+    let end = if let Some(quoted) = rest.strip_prefix('"') {
+      quoted.find('"').map_or(rest.len(), |i| i + 2)
+    } else {
+      rest.find(char::is_whitespace).unwrap_or(rest.len())
+    };
+
+    out.push(unquote(&rest[..end]));
+    rest = rest[end..].trim_start();
+  }
+
+  out
+}
+
+fn flag(value: &str) -> Result<bool, &'static str> {
+  value.trim().parse::<u32>().map(|v| v != 0).map_err(|_| "Landscape flag is not a number.")
+}
+
+impl LandscapeType {
+  fn from_entries(entries: &[(&str, &str)]) -> Result<LandscapeType, &'static str> {
+    let mut id = None;
+    let mut landscape = LandscapeType {
+      id: 0,
+      name: String::new(),
+      texture: String::new(),
+      transitions: vec![],
+      walkable: true,
+      buildable: false,
+      resources: vec![],
+    };
+
+    for (key, value) in entries {
+      match key.to_ascii_lowercase().as_str() {
+        "id" => id = Some(value.trim().parse::<u8>().map_err(|_| "Landscape id is not a number from 0 to 255.")?),
+        "name" => landscape.name = unquote(value).to_string(),
+        "texture" => landscape.texture = unquote(value).to_string(),
+        "transition" => match words(value).as_slice() {
+          [other, mask] => {
+            let other = other.parse::<u8>().map_err(|_| "Landscape transition type is not a number.")?;
+            landscape.transitions.push((other, mask.to_string()));
+          },
+          _ => return Err("Landscape transition needs a type and a mask."),
+        },
+        "walkable" => landscape.walkable = flag(value)?,
+        "buildable" => landscape.buildable = flag(value)?,
+        "resource" => landscape.resources.push(unquote(value).to_string()),
+        _ => {},
+      }
+    }
+
+    landscape.id = id.ok_or("Landscape type without id.")?;
+    if landscape.texture.is_empty() {
+      return Err("Landscape type without texture.");
+    }
+
+    Ok(landscape)
+  }
+}
+
+/// Every `[LandscapeType]` section of a decoded landscape definition file.
+pub fn parse_landscapes(doc: &CifDocument) -> Result<Vec<LandscapeType>, String> {
+  doc.sections().iter()
+    .filter(|(name, _)| name.eq_ignore_ascii_case(SECTION))
+    .enumerate()
+    .map(|(i, (_, entries))| LandscapeType::from_entries(entries).map_err(|err| format!("landscape #{}: {}", i, err)))
+    .collect()
+}
+
+/// Arguments for `create_2d_texture` and `create_2d_texture_masked`: the
+/// PCX files concatenated in `buf`, each stored once, with per layer offsets.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TextureArguments {
+  buf: Vec<u8>,
+  index: Vec<usize>,
+  mask_index: Vec<usize>,
+  offsets: HashMap<String, usize>,
+  /// The `texture_layer` or `transitions` argument of
+  /// `triangulate_attributes` matching the layers.
+  layers: Vec<u32>,
+}
+
+impl TextureArguments {
+  fn add_file(&mut self, vfs: &Vfs, path: &str) -> Result<usize, String> {
+    let key = crate::vfs::normalize_path(path);
+    if let Some(&offset) = self.offsets.get(&key) {
+      return Ok(offset);
+    }
+
+    let data = vfs.read(path).ok_or_else(|| format!("File not found: {}", path))?;
+    let offset = self.buf.len();
+    self.buf.extend_from_slice(&data);
+    self.offsets.insert(key, offset);
+
+    Ok(offset)
+  }
+
+  pub fn buf(&self) -> &[u8] {
+    &self.buf
+  }
+
+  pub fn index(&self) -> &[usize] {
+    &self.index
+  }
+
+  pub fn mask_index(&self) -> &[usize] {
+    &self.mask_index
+  }
+}
+
+fn find(types: &[LandscapeType], id: u8) -> Result<&LandscapeType, String> {
+  types.iter().find(|t| t.id == id).ok_or_else(|| format!("Unknown landscape type {}.", id))
+}
+
+/// `create_2d_texture` arguments with one layer per id in `ids`. `layers`
+/// is indexed by type id; types not in `ids` get `u32::MAX`, which
+/// `triangulate_attributes` rejects, so a missing layer is not mistaken for
+/// layer 0.
+pub fn texture_arguments(types: &[LandscapeType], ids: &[u8], vfs: &Vfs) -> Result<TextureArguments, String> {
+  let mut args = TextureArguments {
+    layers: vec![u32::MAX; ids.iter().max().map_or(0, |&id| id as usize + 1)],
+    ..Default::default()
+  };
+
+  for (layer, id) in ids.iter().enumerate() {
+    let offset = args.add_file(vfs, &find(types, *id)?.texture)?;
+    args.index.push(offset);
+    args.layers[*id as usize] = layer as u32;
+  }
+
+  Ok(args)
+}
+
+/// `create_2d_texture_masked` arguments with one layer per transition between
+/// two of `ids`: the overlay type's texture under its transition mask.
+/// `layers` holds `(base, overlay, layer)` triples, base being the type of
+/// the triangle the overlay is blended onto.
+pub fn transition_arguments(types: &[LandscapeType], ids: &[u8], vfs: &Vfs) -> Result<TextureArguments, String> {
+  let mut args = TextureArguments::default();
+
+  for id in ids.iter() {
+    let overlay = find(types, *id)?;
+
+    for (base, mask) in overlay.transitions.iter().filter(|(base, _)| ids.contains(base)) {
+      let layer = args.index.len() as u32;
+      let offset = args.add_file(vfs, &overlay.texture)?;
+      let mask_offset = args.add_file(vfs, mask)?;
+
+      args.index.push(offset);
+      args.mask_index.push(mask_offset);
+      args.layers.extend_from_slice(&[*base as u32, *id as u32, layer]);
+    }
+  }
+
+  Ok(args)
+}
+
+#[wasm_bindgen]
+impl TextureArguments {
+  #[wasm_bindgen(js_name = buf)]
+  pub fn js_buf(&self) -> Box<[u8]> {
+    self.buf.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = index)]
+  pub fn js_index(&self) -> Box<[usize]> {
+    self.index.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = maskIndex)]
+  pub fn js_mask_index(&self) -> Box<[usize]> {
+    self.mask_index.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = layers)]
+  pub fn js_layers(&self) -> Box<[u32]> {
+    self.layers.clone().into_boxed_slice()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DEFINITIONS: &str = "[LandscapeType]\nid 1\nname \"gras\"\ntexture \"data\\gras.pcx\"\n\
+    [LandscapeType]\nID 3\nname \"sand\"\ntexture \"data\\sand.pcx\"\ntransition 1 \"data\\mask.pcx\"\nwalkable 1\nbuildable 0\nresource \"stone\"\n\
+    [LandscapeType]\nid 9\nname \"water\"\ntexture \"data\\water.pcx\"\ntransition 3 \"data\\mask.pcx\"\nwalkable 0\n\
+    [Other]\nid 4\n";
+
+  fn landscapes() -> Vec<LandscapeType> {
+    parse_landscapes(&CifDocument::from_text(DEFINITIONS, &Default::default()).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_parse_landscapes() {
+    let types = landscapes();
+
+    assert_eq!(types.len(), 3);
+    assert_eq!(types[1], LandscapeType {
+      id: 3,
+      name: "sand".to_string(),
+      texture: "data\\sand.pcx".to_string(),
+      transitions: vec![(1, "data\\mask.pcx".to_string())],
+      walkable: true,
+      buildable: false,
+      resources: vec!["stone".to_string()],
+    });
+    assert!(!types[2].walkable);
+
+    let broken = CifDocument::from_text("[LandscapeType]\nname \"x\"\n", &Default::default()).unwrap();
+    assert!(parse_landscapes(&broken).is_err());
+  }
+
+  #[test]
+  fn test_texture_arguments() {
+    let types = landscapes();
+    let mut vfs = Vfs::new();
+    vfs.mount_files(vec![
+      ("data\\gras.pcx".to_string(), b"GRAS".to_vec()),
+      ("data\\sand.pcx".to_string(), b"SAND".to_vec()),
+      ("data\\mask.pcx".to_string(), b"MASK".to_vec()),
+    ]);
+
+    let args = texture_arguments(&types, &[3, 1], &vfs).unwrap();
+    assert_eq!(args.buf(), b"SANDGRAS");
+    assert_eq!(args.index(), &[0, 4]);
+    assert_eq!(args.layers, vec![u32::MAX, 1, u32::MAX, 0]);
+
+    let args = texture_arguments(&types, &[3, 3], &vfs).unwrap();
+    assert_eq!(args.buf(), b"SAND");
+
+    // Water has no texture in the file system but is not asked for.
+    let args = transition_arguments(&types, &[1, 3], &vfs).unwrap();
+    assert_eq!(args.buf(), b"SANDMASK");
+    assert_eq!((args.index(), args.mask_index()), (&[0][..], &[4][..]));
+    assert_eq!(args.layers, vec![1, 3, 0]);
+
+    assert!(texture_arguments(&types, &[9], &vfs).is_err());
+    assert!(texture_arguments(&types, &[5], &vfs).is_err());
+  }
+}
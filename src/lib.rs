@@ -2,46 +2,368 @@ use web_sys::console;
 
 mod utils;
 mod tessellate;
+mod heightfield;
+mod lod;
+#[cfg(feature = "unverified-formats")]
+mod lib_archive;
 mod pcx;
 mod bmd;
 mod timer;
+mod palette;
+mod quantize;
+mod transition;
+mod water;
+mod vfs;
+mod cif;
+#[cfg(feature = "unverified-formats")]
+mod landscape;
+#[cfg(feature = "unverified-formats")]
+mod graphics;
+#[cfg(feature = "unverified-formats")]
+mod map;
+#[cfg(feature = "unverified-formats")]
+mod font;
+mod reader;
+mod codepage;
 
 use wasm_bindgen::prelude::*;
 
+pub use tessellate::{BorderMode, TessellationOptions};
+pub use heightfield::Heightfield;
+pub use water::Water;
+pub use vfs::Vfs;
+#[cfg(feature = "unverified-formats")]
+pub use lib_archive::LibArchive;
+#[cfg(feature = "unverified-formats")]
+pub use landscape::TextureArguments;
+#[cfg(feature = "unverified-formats")]
+pub use map::Map;
+#[cfg(feature = "unverified-formats")]
+pub use font::Font;
+
+use std::borrow::Cow;
+
 // #[cfg(feature = "wee_alloc")]
 // #[global_allocator]
 // static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+/// Two triangles per cell as `(x, y)` pairs, 12 floats per cell. Without
+/// `options` the defaults are used, as before options existed.
 #[wasm_bindgen]
-pub fn triangulate(w: usize, h: usize, elevation: &[u8]) -> Box<[f32]> {
+pub fn triangulate(w: usize, h: usize, elevation: &[u8], options: Option<TessellationOptions>) -> Box<[f32]> {
   let _timer = timer::Timer::new("triangulate");
 
   let mut tris = vec![0.0; w * h * 2 * 2 * 3];
-  tessellate::triangulate_map(&mut tris, w, h, elevation);
+  tessellate::triangulate_map(&mut tris, w, h, elevation, &options.unwrap_or_default());
 
   return tris.into_boxed_slice();
 }
 
+/// Re-tessellates the cells of a `triangulate` buffer touching the edited
+/// elevation samples `region` (`[x0, y0, x1, y1]`, inclusive). Returns the
+/// `[start, end)` byte range of `mesh` that changed, empty when nothing did.
+#[wasm_bindgen]
+pub fn update_region(mesh: &mut [f32], w: usize, h: usize, elevation: &[u8], region: &[usize], options: &TessellationOptions) -> Result<Box<[u32]>, JsValue> {
+  if mesh.len() < w * h * 12 {
+    return Err(JsValue::from_str("update_region: mesh buffer is smaller than the map."));
+  }
+  if region.len() != 4 {
+    return Err(JsValue::from_str("update_region: region must be [x0, y0, x1, y1]."));
+  }
+
+  let region = (region[0], region[1], region[2], region[3]);
+  let range = tessellate::update_region(mesh, w, h, elevation, region, options).unwrap_or((0, 0));
+
+  Ok(vec![range.0 as u32, range.1 as u32].into_boxed_slice())
+}
+
+/// Map cell under a point in the coordinate space of `triangulate`, as
+/// `[cell, triangle, b0, b1, b2]` with the barycentric weights of the hit
+/// triangle's vertices.
+#[wasm_bindgen]
+pub fn pick(w: usize, h: usize, elevation: &[u8], x: f32, y: f32, options: &TessellationOptions) -> Option<Box<[f32]>> {
+  tessellate::pick(w, h, elevation, x, y, options).map(|p| {
+    vec![p.cell as f32, p.triangle as f32, p.barycentric[0], p.barycentric[1], p.barycentric[2]].into_boxed_slice()
+  })
+}
+
+/// Positions of the cells in chunk `(cx, cy)` when the map is split into
+/// `chunk_size`-cell squares, in the layout of `triangulate`.
+#[wasm_bindgen]
+pub fn triangulate_chunk(w: usize, h: usize, elevation: &[u8], chunk_size: usize, cx: usize, cy: usize, options: &TessellationOptions) -> Result<Box<[f32]>, JsValue> {
+  if chunk_size == 0 {
+    return Err(JsValue::from_str("triangulate_chunk: chunk_size must be positive."));
+  }
+
+  let chunk = tessellate::chunk_at(w, h, chunk_size, cx, cy).ok_or_else(|| JsValue::from_str("triangulate_chunk: chunk is outside the map."))?;
+
+  Ok(tessellate::triangulate_chunk(w, h, elevation, &chunk, options).into_boxed_slice())
+}
+
+/// Row-major indices of the chunks intersecting the camera rectangle `view`
+/// (`[x0, y0, x1, y1]`), in the coordinate space of `triangulate`.
+#[wasm_bindgen]
+pub fn visible_chunks(w: usize, h: usize, elevation: &[u8], chunk_size: usize, view: &[f32], options: &TessellationOptions) -> Result<Box<[u32]>, JsValue> {
+  if chunk_size == 0 {
+    return Err(JsValue::from_str("visible_chunks: chunk_size must be positive."));
+  }
+  if view.len() != 4 {
+    return Err(JsValue::from_str("visible_chunks: view must be [x0, y0, x1, y1]."));
+  }
+
+  let visible = tessellate::visible_chunks(w, h, elevation, chunk_size, (view[0], view[1], view[2], view[3]), options);
+
+  Ok(visible.iter().map(|&c| c as u32).collect::<Vec<u32>>().into_boxed_slice())
+}
+
+/// Height error of every chunk at each LOD level, `lod_max_level(chunk_size)
+/// + 1` values per chunk in row-major order.
+#[wasm_bindgen]
+pub fn lod_errors(w: usize, h: usize, elevation: &[u8], chunk_size: usize, options: &TessellationOptions) -> Result<Box<[f32]>, JsValue> {
+  let _timer = timer::Timer::new("lod_errors");
+
+  if chunk_size == 0 {
+    return Err(JsValue::from_str("lod_errors: chunk_size must be positive."));
+  }
+
+  Ok(lod::chunk_lod_errors(w, h, elevation, chunk_size, options).into_boxed_slice())
+}
+
+#[wasm_bindgen]
+pub fn lod_max_level(chunk_size: usize) -> usize {
+  lod::max_level(chunk_size)
+}
+
+/// Coarsest level per chunk whose error from `lod_errors` is at most
+/// `max_error`.
+#[wasm_bindgen]
+pub fn lod_levels(errors: &[f32], chunk_size: usize, max_error: f32) -> Box<[u8]> {
+  lod::select_levels(errors, chunk_size, max_error).into_boxed_slice()
+}
+
+/// Indices into `triangulate_vertices` for chunk `(cx, cy)` at its level in
+/// `levels`, stitched to its neighbours so chunks at different levels meet
+/// without cracks.
+#[wasm_bindgen]
+pub fn triangulate_chunk_lod(w: usize, h: usize, chunk_size: usize, cx: usize, cy: usize, levels: &[u8]) -> Result<Box<[u32]>, JsValue> {
+  if chunk_size == 0 {
+    return Err(JsValue::from_str("triangulate_chunk_lod: chunk_size must be positive."));
+  }
+
+  let indices = lod::triangulate_chunk_lod(w, h, chunk_size, cx, cy, levels).map_err(|err| JsValue::from_str(&format!("triangulate_chunk_lod: {}", err)))?;
+
+  Ok(indices.into_boxed_slice())
+}
+
+/// Shared vertex buffer for `triangulate_indices`: one `(x, y)` pair per
+/// sample of the staggered elevation grid.
+#[wasm_bindgen]
+pub fn triangulate_vertices(w: usize, h: usize, elevation: &[u8], options: &TessellationOptions) -> Box<[f32]> {
+  let _timer = timer::Timer::new("triangulate_vertices");
+
+  tessellate::triangulate_map_vertices(w, h, elevation, options).into_boxed_slice()
+}
+
+#[wasm_bindgen]
+pub fn triangulate_indices(w: usize, h: usize, options: &TessellationOptions) -> Box<[u32]> {
+  let _timer = timer::Timer::new("triangulate_indices");
+
+  tessellate::triangulate_map_indices(w, h, options).into_boxed_slice()
+}
+
+/// 16-bit variant of `triangulate_indices` for maps with at most 65536
+/// vertices.
+#[wasm_bindgen]
+pub fn triangulate_indices_u16(w: usize, h: usize, options: &TessellationOptions) -> Result<Box<[u16]>, JsValue> {
+  if tessellate::vertex_count(w, h) > 0x10000 {
+    return Err(JsValue::from_str("triangulate_indices_u16: map has too many vertices for 16-bit indices."));
+  }
+
+  let indices = tessellate::triangulate_map_indices(w, h, options);
+
+  Ok(indices.iter().map(|&i| i as u16).collect::<Vec<u16>>().into_boxed_slice())
+}
+
+/// Per-vertex `(nx, ny, nz)` normals for `triangulate_vertices`, or for the
+/// six vertices per cell of `triangulate` when `flat` is set.
+#[wasm_bindgen]
+pub fn triangulate_normals(w: usize, h: usize, elevation: &[u8], flat: bool, options: &TessellationOptions) -> Box<[f32]> {
+  let _timer = timer::Timer::new("triangulate_normals");
+
+  let normals = tessellate::triangulate_map_normals(w, h, elevation, options);
+  let normals = if flat {
+    tessellate::expand_indexed(&normals, 3, &tessellate::cell_indices(w, h))
+  } else {
+    normals
+  };
+
+  normals.into_boxed_slice()
+}
+
+/// Baked per-vertex light in `[ambient, 1]` for a sun in direction `sun`
+/// (`[x, y, z]`), laid out like `triangulate_normals`.
 #[wasm_bindgen]
-pub fn create_2d_texture_masked(w: usize, h: usize, buf: &[u8], index: &[usize], mask_index: &[usize]) -> Box<[u8]> {
+pub fn triangulate_light(w: usize, h: usize, elevation: &[u8], sun: &[f32], ambient: f32, flat: bool, options: &TessellationOptions) -> Result<Box<[f32]>, JsValue> {
+  let _timer = timer::Timer::new("triangulate_light");
+
+  if sun.len() != 3 {
+    return Err(JsValue::from_str("triangulate_light: sun must be an [x, y, z] direction."));
+  }
+
+  let normals = tessellate::triangulate_map_normals(w, h, elevation, options);
+  let light = tessellate::hillshade(&normals, [sun[0], sun[1], sun[2]], ambient);
+  let light = if flat {
+    tessellate::expand_indexed(&light, 1, &tessellate::cell_indices(w, h))
+  } else {
+    light
+  };
+
+  Ok(light.into_boxed_slice())
+}
+
+/// Texture attributes for `triangulate`: `(u, v, layer, transition_layer,
+/// variant)` per vertex. `transitions` is a flat list of `(own type, other
+/// type, mask layer)` triples.
+#[wasm_bindgen]
+pub fn triangulate_attributes(w: usize, h: usize, types_a: &[u8], types_b: &[u8], texture_layer: &[u32], transitions: &[u32], options: &TessellationOptions) -> Result<Box<[f32]>, JsValue> {
+  let _timer = timer::Timer::new("triangulate_attributes");
+
+  let attributes = tessellate::transition_triples(transitions)
+    .and_then(|transitions| tessellate::triangulate_map_attributes(w, h, types_a, types_b, texture_layer, &transitions, options))
+    .map_err(|err| JsValue::from_str(&format!("triangulate_attributes: {}", err)))?;
+
+  Ok(attributes.into_boxed_slice())
+}
+
+/// `mask_channel` selects how mask pixels become alpha: 0 raw palette index,
+/// 1 luminance, 2 red, 3 green, 4 blue. Without it the mask's luminance is
+/// used. Other values are an error.
+pub(crate) fn mask_alpha(mask_channel: Option<u8>, mask_threshold: Option<u8>, caller: &str) -> Result<pcx::MaskAlpha, JsValue> {
+  let channel = match mask_channel {
+    None => pcx::MaskAlpha::default().channel,
+    Some(c) => pcx::MaskChannel::from_u8(c)
+      .ok_or_else(|| JsValue::from_str(&format!("{}: unknown mask channel {}", caller, c)))?,
+  };
+
+  Ok(pcx::MaskAlpha { channel, threshold: mask_threshold })
+}
+
+/// See `mask_alpha` for `mask_channel`. With `mask_threshold` set, alpha is
+/// binary.
+///
+/// Deprecated: takes PCX offsets into one buffer; use
+/// `create_2d_texture_masked_from_vfs`, which resolves game paths.
+#[wasm_bindgen]
+pub fn create_2d_texture_masked(w: usize, h: usize, buf: &[u8], index: &[usize], mask_index: &[usize], mask_channel: Option<u8>, mask_threshold: Option<u8>) -> Result<Box<[u8]>, JsValue> {
   let _timer = timer::Timer::new("create_2d_texture_masked");
 
+  let mask_alpha = mask_alpha(mask_channel, mask_threshold, "create_2d_texture_masked")?;
+
   let mut out = vec![0u8; w * h * index.len() * 4];
-  pcx::pcx_texture_array(&buf, &mut out[..], &index, Some(&mask_index));
+  pcx::pcx_texture_array(&buf, &mut out[..], &index, Some(&mask_index), &mask_alpha)
+    .map_err(|err| JsValue::from_str(&format!("create_2d_texture_masked: {}", err)))?;
 
-  return out.into_boxed_slice();
+  Ok(out.into_boxed_slice())
 }
 
+/// Deprecated: takes PCX offsets into one buffer; use
+/// `create_2d_texture_from_vfs`, which resolves game paths.
 #[wasm_bindgen]
-pub fn create_2d_texture(w: usize, h: usize, buf: &[u8], index: &[usize]) -> Box<[u8]> {
+pub fn create_2d_texture(w: usize, h: usize, buf: &[u8], index: &[usize]) -> Result<Box<[u8]>, JsValue> {
   let _timer = timer::Timer::new("create_2d_texture");
 
   let mut out = vec![0u8; w * h * index.len() * 4];
-  pcx::pcx_texture_array(&buf, &mut out[..], &index, None);
+  pcx::pcx_texture_array(&buf, &mut out[..], &index, None, &pcx::MaskAlpha::default())
+    .map_err(|err| JsValue::from_str(&format!("create_2d_texture: {}", err)))?;
 
-  return out.into_boxed_slice();
+  Ok(out.into_boxed_slice())
 }
 
+/// Renders the blended transition tiles between two landscape textures, one
+/// RGBA layer per `transition::TRANSITION_VARIANTS` entry. `base_index`,
+/// `overlay_index` and `mask_index` are offsets of the PCX files in `buf`. The
+/// layer size is taken from the PCX headers, which must all agree.
+///
+/// Deprecated: use `create_transition_textures_from_vfs`, which resolves
+/// game paths.
+#[wasm_bindgen]
+pub fn create_transition_textures(buf: &[u8], base_index: usize, overlay_index: usize, mask_index: usize, mask_channel: Option<u8>, mask_threshold: Option<u8>) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("create_transition_textures");
+
+  let mask_alpha = mask_alpha(mask_channel, mask_threshold, "create_transition_textures")?;
+
+  let image = |offset: usize| buf.get(offset..).ok_or(pcx::PcxError::OffsetOutOfBounds(offset));
+
+  let out = image(base_index)
+    .and_then(|base| {
+      let mut out = vec![0u8; transition::transition_set_len(base)?];
+      transition::transition_set(base, image(overlay_index)?, image(mask_index)?, &mask_alpha, &mut out)?;
+      Ok(out)
+    })
+    .map_err(|err| JsValue::from_str(&format!("create_transition_textures: {}", err)))?;
+
+  Ok(out.into_boxed_slice())
+}
+
+/// Converts a palette file (`raw`, `act`, `jasc` or `gpl`) into the raw
+/// 768-byte layout used throughout the pipeline.
+#[wasm_bindgen]
+pub fn palette_import(buf: &[u8], format: &str) -> Result<Box<[u8]>, JsValue> {
+  let format = palette::PaletteFormat::from_name(format).ok_or_else(|| JsValue::from_str("Unknown palette format."))?;
+  let palette = palette::Palette::import(buf, format).map_err(JsValue::from_str)?;
+
+  Ok(palette.to_raw().into_boxed_slice())
+}
+
+#[wasm_bindgen]
+pub fn palette_export(raw: &[u8], format: &str) -> Result<Box<[u8]>, JsValue> {
+  let format = palette::PaletteFormat::from_name(format).ok_or_else(|| JsValue::from_str("Unknown palette format."))?;
+  let palette = palette::Palette::from_raw(raw).map_err(JsValue::from_str)?;
+
+  Ok(palette.export(format).into_boxed_slice())
+}
+
+/// Index of the closest colour of a raw 768-byte palette for every RGB
+/// triplet in `rgb`, matched as `quantize_rgba` does.
+#[wasm_bindgen]
+pub fn palette_nearest(raw: &[u8], rgb: &[u8]) -> Result<Box<[u8]>, JsValue> {
+  let palette = palette::Palette::from_raw(raw).map_err(JsValue::from_str)?;
+  if !rgb.len().is_multiple_of(3) {
+    return Err(JsValue::from_str("palette_nearest: rgb must be whole triplets."));
+  }
+
+  Ok(rgb.chunks(3).map(|c| palette.nearest([c[0], c[1], c[2]])).collect::<Vec<_>>().into_boxed_slice())
+}
+
+/// Maps RGBA pixels onto indices of a raw 768-byte palette. `dither` is 0 for
+/// none, 1 for Floyd-Steinberg and 2 for ordered. `reserved` holds inclusive
+/// `[first, last]` index pairs that are never used; an odd length is an
+/// error.
+#[wasm_bindgen]
+pub fn quantize_rgba(w: usize, h: usize, rgba: &[u8], palette_buf: &[u8], dither: u8, reserved: &[u8], transparent_index: Option<u8>) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("quantize_rgba");
+
+  let palette = palette::Palette::from_raw(palette_buf).map_err(JsValue::from_str)?;
+  if !reserved.len().is_multiple_of(2) {
+    return Err(JsValue::from_str("quantize_rgba: reserved must hold [first, last] pairs."));
+  }
+  let options = quantize::QuantizeOptions {
+    dither: quantize::Dither::from_u8(dither).ok_or_else(|| JsValue::from_str("Unknown dither mode."))?,
+    reserved: reserved.chunks(2).map(|c| (c[0], c[1])).collect(),
+    transparent_index,
+  };
+
+  let out = quantize::quantize(rgba, w, h, &palette, &options).map_err(JsValue::from_str)?;
+
+  Ok(out.into_boxed_slice())
+}
+
+#[wasm_bindgen]
+pub fn pcx_encode(w: usize, h: usize, pixels: &[u8], palette_buf: &[u8]) -> Result<Box<[u8]>, JsValue> {
+  let out = pcx::pcx_write(w, h, pixels, palette_buf).map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+  Ok(out.into_boxed_slice())
+}
 
 #[inline]
 fn write_uint32_le(buf: &mut [u8], val: u32) {
@@ -51,19 +373,37 @@ fn write_uint32_le(buf: &mut [u8], val: u32) {
   buf[3] = ((val & 0xFF000000) >> 24) as u8;
 }
 
+/// `(frames, width, height, encoded length)` per BMD of `buf`, where the
+/// BMDs are stored back to back, each followed by its shadow where
+/// `has_shadow` is set.
 #[wasm_bindgen]
-pub fn create_bmd_texture_array(bmd_buf: &[u8], palette_buf: &[u8], bmd_index: &[usize], bmd_frame_instance_count: &[usize], has_shadow: &[u8], palette_index: &[usize], frame_palette_index: &[usize]) -> Box<[u8]> {
-  let _timer = timer::Timer::new("create_bmd_texture_array");
+pub fn bmd_stats(buf: &[u8], has_shadow: &[u8]) -> Result<Box<[u32]>, JsValue> {
+  let stats = bmd::bmd_stats(buf, has_shadow, has_shadow.len()).map_err(|err| JsValue::from_str(&format!("bmd_stats: {}", err)))?;
+
+  Ok(stats.iter().flat_map(|s| vec![s.frames as u32, s.width as u32, s.height as u32, s.encoded_length as u32]).collect::<Vec<_>>().into_boxed_slice())
+}
+
+/// Decodes BMDs with their optional shadows into the texture array layout
+/// of `create_bmd_texture_array`.
+///
+/// `frame_palette_index` starts with `bmds.len()` entries that are skipped
+/// (callers put the frame instance counts there), followed by one
+/// `(frame, palette)` pair per frame instance, BMD after BMD.
+fn bmd_texture_array(bmds: &[(&[u8], Option<&[u8]>)], palettes: &Vec<&[u8]>, bmd_frame_instance_count: &[usize], frame_palette_index: &[usize]) -> Result<Vec<u8>, String> {
+  if bmd_frame_instance_count.len() < bmds.len() {
+    return Err("need one frame instance count per BMD.".to_string());
+  }
 
-  let palettes = pcx::pcx_read_palette_array(palette_buf, palette_index);
-  let bmd_stats = bmd::bmd_stats(bmd_buf, has_shadow, bmd_index.len());
+  let bmd_stats = bmds.iter().enumerate()
+    .map(|(i, (bmd, shadow))| bmd::bmd_stat(bmd, *shadow).map_err(|err| format!("BMD #{}: {}", i, err)))
+    .collect::<Result<Vec<bmd::BmdStats>, String>>()?;
   let total_buf_length = bmd_stats.iter().zip(bmd_frame_instance_count).fold(0, |r, (s, c)| r + 4 * 4 + c * (2 * 4 + s.encoded_length));
 
   let mut images = vec![0u8; total_buf_length];
   let mut out_ptr = 0usize;
   let mut frame_ptr = 0;
 
-  for i in 0..bmd_index.len() {
+  for (i, (bmd, shadow)) in bmds.iter().enumerate() {
     let s = &bmd_stats[i];
 
     // console::log_1(&format!("out_ptr was {}", out_ptr).into());
@@ -77,13 +417,295 @@ pub fn create_bmd_texture_array(bmd_buf: &[u8], palette_buf: &[u8], bmd_index: &
     // Write texture 2d image
     let frame_instance_count = bmd_frame_instance_count[i];
 
-    let mut it = frame_palette_index[bmd_index.len() + frame_ptr..bmd_index.len() + frame_ptr + frame_instance_count * 2].chunks(2).map(|c| (&c[0], &c[1]));
+    let start = bmds.len() + frame_ptr;
+    let pairs = frame_palette_index.get(start..start + frame_instance_count * 2)
+      .ok_or_else(|| format!("BMD #{}: frame_palette_index is too short.", i))?;
+    let mut it = pairs.chunks(2).map(|c| (&c[0], &c[1]));
     frame_ptr += frame_instance_count * 2;
 
-    out_ptr += bmd::read_bmd(s.width, s.height, bmd_frame_instance_count[i], has_shadow[i] > 0, &bmd_buf[bmd_index[i]..], &mut images[out_ptr..], &mut it, &palettes, false);
+    out_ptr += bmd::read_bmd(s.width, s.height, bmd_frame_instance_count[i], bmd, *shadow, &mut images[out_ptr..], &mut it, palettes, false)
+      .map_err(|err| format!("BMD #{}: {}", i, err))?;
     // console::log_1(&format!("out_ptr is {}", out_ptr).into());
     // out_ptr += 2 * 4 * frame_instance_count + bmd_frame_instance_count[i] * s.encoded_length;
   }
 
-  return images.into_boxed_slice();
+  Ok(images)
+}
+
+/// Texture array of the BMDs at `bmd_index` in `bmd_buf`, each followed by
+/// its shadow where `has_shadow` is set, colored with the palettes at
+/// `palette_index` in `palette_buf`. `frame_palette_index` holds one entry
+/// per BMD, which is skipped, then `(frame, palette)` pairs for the
+/// `bmd_frame_instance_count` instances of every BMD.
+///
+/// Deprecated: use `create_bmd_texture_array_from_vfs`, which resolves game
+/// paths and finds shadows without offsets.
+#[wasm_bindgen]
+pub fn create_bmd_texture_array(bmd_buf: &[u8], palette_buf: &[u8], bmd_index: &[usize], bmd_frame_instance_count: &[usize], has_shadow: &[u8], palette_index: &[usize], frame_palette_index: &[usize]) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("create_bmd_texture_array");
+
+  let palettes = pcx::pcx_read_palette_array(palette_buf, palette_index)
+    .map_err(|err| JsValue::from_str(&format!("create_bmd_texture_array: palette {}", err)))?;
+
+  // A BMD's shadow is stored right after it.
+  let bmds = bmd_index.iter().zip(has_shadow).enumerate().map(|(i, (&offset, &shadow))| {
+    let (bmd, rest) = bmd_buf.get(offset..)
+      .ok_or("offset is past the end of the buffer.")
+      .and_then(bmd::bmd_split)
+      .map_err(|err| format!("BMD #{}: {}", i, err))?;
+    let shadow = match shadow {
+      0 => None,
+      _ => Some(bmd::bmd_split(rest).map_err(|err| format!("BMD #{} shadow: {}", i, err))?.0),
+    };
+
+    Ok((bmd, shadow))
+  }).collect::<Result<Vec<(&[u8], Option<&[u8]>)>, String>>();
+
+  let images = bmds.and_then(|bmds| bmd_texture_array(&bmds, &palettes, bmd_frame_instance_count, frame_palette_index))
+    .map_err(|err| JsValue::from_str(&format!("create_bmd_texture_array: {}", err)))?;
+
+  Ok(images.into_boxed_slice())
+}
+
+/// Game paths passed from JS as strings.
+fn js_paths(paths: &[JsValue]) -> Result<Vec<String>, JsValue> {
+  paths.iter().map(|p| p.as_string().ok_or_else(|| JsValue::from_str("Paths must be strings."))).collect()
+}
+
+fn read_all<'a>(vfs: &'a Vfs, paths: &[JsValue], caller: &str) -> Result<Vec<Cow<'a, [u8]>>, JsValue> {
+  vfs.read_all(&js_paths(paths)?).map_err(|err| JsValue::from_str(&format!("{}: {}", caller, err)))
+}
+
+fn slices<'a>(files: &'a [Cow<[u8]>]) -> Vec<&'a [u8]> {
+  files.iter().map(|f| f.as_ref()).collect()
+}
+
+/// Plain INI-like text of an encoded `.cif` file.
+#[wasm_bindgen]
+pub fn cif_decode(buf: &[u8]) -> Result<String, JsValue> {
+  let doc = cif::read_cif(buf).map_err(|err| JsValue::from_str(&format!("cif_decode: {}", err)))?;
+
+  Ok(doc.to_text())
+}
+
+/// Encodes text in the form `cif_decode` returns; `#` lines that are not
+/// `#<type> text` are comments. The header and layout are taken from
+/// `original`, the file the text was decoded from, when it is not empty, so
+/// unchanged lines are written back where they were, see `cif::write_cif`.
+#[wasm_bindgen]
+pub fn cif_encode(text: &str, original: &[u8]) -> Result<Box<[u8]>, JsValue> {
+  let original = if original.is_empty() {
+    cif::CifDocument::default()
+  } else {
+    cif::read_cif(original).map_err(|err| JsValue::from_str(&format!("cif_encode: {}", err)))?
+  };
+
+  let doc = cif::CifDocument::from_text(text, &original).map_err(|err| JsValue::from_str(&format!("cif_encode: {}", err)))?;
+  let out = cif::write_cif(&doc).map_err(|err| JsValue::from_str(&format!("cif_encode: {}", err)))?;
+
+  Ok(out.into_boxed_slice())
+}
+
+#[cfg(feature = "unverified-formats")]
+fn landscapes(definitions: &[u8], caller: &str) -> Result<Vec<landscape::LandscapeType>, JsValue> {
+  let doc = cif::read_cif(definitions).map_err(|err| JsValue::from_str(&format!("{}: {}", caller, err)))?;
+
+  landscape::parse_landscapes(&doc).map_err(|err| JsValue::from_str(&format!("{}: {}", caller, err)))
+}
+
+/// `create_2d_texture` arguments for the landscape types `ids`, read from the
+/// encoded landscape definitions and the textures in `vfs`.
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+pub fn landscape_texture_arguments(vfs: &Vfs, definitions: &[u8], ids: &[u8]) -> Result<TextureArguments, JsValue> {
+  let types = landscapes(definitions, "landscape_texture_arguments")?;
+
+  landscape::texture_arguments(&types, ids, vfs).map_err(|err| JsValue::from_str(&format!("landscape_texture_arguments: {}", err)))
+}
+
+/// `create_2d_texture_masked` arguments for the transitions between the
+/// landscape types `ids`.
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+pub fn landscape_transition_arguments(vfs: &Vfs, definitions: &[u8], ids: &[u8]) -> Result<TextureArguments, JsValue> {
+  let types = landscapes(definitions, "landscape_transition_arguments")?;
+
+  landscape::transition_arguments(&types, ids, vfs).map_err(|err| JsValue::from_str(&format!("landscape_transition_arguments: {}", err)))
+}
+
+/// Builds a `.lib` archive from files concatenated in `data`, `sizes[i]`
+/// bytes each, stored under `paths[i]`.
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+pub fn lib_build(paths: Box<[JsValue]>, data: &[u8], sizes: &[usize]) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("lib_build");
+
+  let paths = js_paths(&paths)?;
+  if sizes.len() != paths.len() || sizes.iter().sum::<usize>() != data.len() {
+    return Err(JsValue::from_str("lib_build: sizes do not match the paths and data."));
+  }
+
+  let mut builder = lib_archive::LibBuilder::new();
+  let mut offset = 0;
+  for (path, size) in paths.iter().zip(sizes) {
+    builder.add(path, data[offset..offset + size].to_vec());
+    offset += size;
+  }
+
+  let out = builder.build().map_err(|err| JsValue::from_str(&format!("lib_build: {}", err)))?;
+
+  Ok(out.into_boxed_slice())
+}
+
+/// `create_2d_texture` with the PCX files given by their game paths in `vfs`.
+#[wasm_bindgen]
+pub fn create_2d_texture_from_vfs(w: usize, h: usize, vfs: &Vfs, paths: Box<[JsValue]>) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("create_2d_texture_from_vfs");
+
+  let images = read_all(vfs, &paths, "create_2d_texture_from_vfs")?;
+
+  let mut out = vec![0u8; w * h * images.len() * 4];
+  pcx::pcx_images_texture_array(&slices(&images), None, &mut out, &pcx::MaskAlpha::default())
+    .map_err(|err| JsValue::from_str(&format!("create_2d_texture_from_vfs: {}", err)))?;
+
+  Ok(out.into_boxed_slice())
+}
+
+/// `create_2d_texture_masked` with the PCX files and masks given by their
+/// game paths in `vfs`.
+#[wasm_bindgen]
+pub fn create_2d_texture_masked_from_vfs(w: usize, h: usize, vfs: &Vfs, paths: Box<[JsValue]>, mask_paths: Box<[JsValue]>, mask_channel: Option<u8>, mask_threshold: Option<u8>) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("create_2d_texture_masked_from_vfs");
+
+  let mask_alpha = mask_alpha(mask_channel, mask_threshold, "create_2d_texture_masked_from_vfs")?;
+
+  let images = read_all(vfs, &paths, "create_2d_texture_masked_from_vfs")?;
+  let masks = read_all(vfs, &mask_paths, "create_2d_texture_masked_from_vfs")?;
+
+  let mut out = vec![0u8; w * h * images.len() * 4];
+  pcx::pcx_images_texture_array(&slices(&images), Some(&slices(&masks)), &mut out, &mask_alpha)
+    .map_err(|err| JsValue::from_str(&format!("create_2d_texture_masked_from_vfs: {}", err)))?;
+
+  Ok(out.into_boxed_slice())
+}
+
+/// `create_transition_textures` with the PCX files given by their game paths
+/// in `vfs`.
+#[wasm_bindgen]
+pub fn create_transition_textures_from_vfs(vfs: &Vfs, base_path: &str, overlay_path: &str, mask_path: &str, mask_channel: Option<u8>, mask_threshold: Option<u8>) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("create_transition_textures_from_vfs");
+
+  let mask_alpha = mask_alpha(mask_channel, mask_threshold, "create_transition_textures_from_vfs")?;
+
+  let paths = [base_path.to_string(), overlay_path.to_string(), mask_path.to_string()];
+  let files = vfs.read_all(&paths).map_err(|err| JsValue::from_str(&format!("create_transition_textures_from_vfs: {}", err)))?;
+
+  let out = transition::transition_set_len(&files[0])
+    .and_then(|len| {
+      let mut out = vec![0u8; len];
+      transition::transition_set(&files[0], &files[1], &files[2], &mask_alpha, &mut out)?;
+      Ok(out)
+    })
+    .map_err(|err| JsValue::from_str(&format!("create_transition_textures_from_vfs: {}", err)))?;
+
+  Ok(out.into_boxed_slice())
+}
+
+fn bmd_texture_array_from_vfs(vfs: &Vfs, bmd_paths: &[String], shadow_paths: &[Option<String>], palette_paths: &[String], bmd_frame_instance_count: &[usize], frame_palette_index: &[usize], caller: &str) -> Result<Vec<u8>, JsValue> {
+  let palette_files = vfs.read_all(palette_paths).map_err(|err| JsValue::from_str(&format!("{}: {}", caller, err)))?;
+  let palettes = pcx::pcx_palettes(&slices(&palette_files))
+    .map_err(|err| JsValue::from_str(&format!("{}: palette {}", caller, err)))?;
+
+  let bmd_files = vfs.read_all(bmd_paths).map_err(|err| JsValue::from_str(&format!("{}: {}", caller, err)))?;
+  if shadow_paths.len() < bmd_files.len() {
+    return Err(JsValue::from_str(&format!("{}: need one shadow path per BMD.", caller)));
+  }
+
+  let mut shadow_files = Vec::with_capacity(bmd_files.len());
+  for path in shadow_paths.iter().take(bmd_files.len()) {
+    shadow_files.push(match path {
+      Some(path) => Some(vfs.read(path).ok_or_else(|| JsValue::from_str(&format!("{}: File not found: {}", caller, path)))?),
+      None => None,
+    });
+  }
+
+  let bmds: Vec<(&[u8], Option<&[u8]>)> = bmd_files.iter().zip(shadow_files.iter())
+    .map(|(bmd, shadow)| (bmd.as_ref(), shadow.as_deref()))
+    .collect();
+
+  bmd_texture_array(&bmds, &palettes, bmd_frame_instance_count, frame_palette_index)
+    .map_err(|err| JsValue::from_str(&format!("{}: {}", caller, err)))
+}
+
+/// `create_bmd_texture_array` with BMDs and palette PCX files given by their
+/// game paths in `vfs`. An empty shadow path means the BMD has no shadow;
+/// `frame_palette_index` skips one entry per BMD as there, and its pairs
+/// refer to positions in `palette_paths`.
+#[wasm_bindgen]
+pub fn create_bmd_texture_array_from_vfs(vfs: &Vfs, bmd_paths: Box<[JsValue]>, shadow_paths: Box<[JsValue]>, palette_paths: Box<[JsValue]>, bmd_frame_instance_count: &[usize], frame_palette_index: &[usize]) -> Result<Box<[u8]>, JsValue> {
+  let _timer = timer::Timer::new("create_bmd_texture_array_from_vfs");
+
+  let shadow_paths: Vec<Option<String>> = js_paths(&shadow_paths)?.into_iter().map(|p| Some(p).filter(|p| !p.is_empty())).collect();
+  let out = bmd_texture_array_from_vfs(vfs, &js_paths(&bmd_paths)?, &shadow_paths, &js_paths(&palette_paths)?, bmd_frame_instance_count, frame_palette_index, "create_bmd_texture_array_from_vfs")?;
+
+  Ok(out.into_boxed_slice())
+}
+
+/// A texture array built from graphics definitions, with the layers of every
+/// requested object.
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+pub struct GfxTextureArray {
+  data: Vec<u8>,
+  lookup: Vec<(String, graphics::GfxLayers)>,
+}
+
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+impl GfxTextureArray {
+  /// The array in the layout of `create_bmd_texture_array`. It is moved out
+  /// rather than copied, so later calls return an empty array.
+  pub fn data(&mut self) -> Box<[u8]> {
+    std::mem::take(&mut self.data).into_boxed_slice()
+  }
+
+  pub fn names(&self) -> Box<[JsValue]> {
+    self.lookup.iter().map(|(name, _)| JsValue::from_str(name)).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  /// `(bmd, first, count)` per name: the block in `data` and the range of
+  /// frame instances within it.
+  pub fn layers(&self) -> Box<[u32]> {
+    self.lookup.iter().flat_map(|(_, l)| vec![l.bmd as u32, l.first as u32, l.count as u32]).collect::<Vec<_>>().into_boxed_slice()
+  }
+}
+
+/// Renders the objects `names` of the encoded graphics definitions with the
+/// BMDs and palettes in `vfs`.
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+pub fn create_gfx_texture_array(vfs: &Vfs, definitions: &[u8], names: Box<[JsValue]>) -> Result<GfxTextureArray, JsValue> {
+  let _timer = timer::Timer::new("create_gfx_texture_array");
+
+  let doc = cif::read_cif(definitions).map_err(|err| JsValue::from_str(&format!("create_gfx_texture_array: {}", err)))?;
+  let defs = graphics::parse_graphics(&doc).map_err(|err| JsValue::from_str(&format!("create_gfx_texture_array: {}", err)))?;
+
+  let names = js_paths(&names)?;
+  let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+  let plan = defs.plan(&names).map_err(|err| JsValue::from_str(&format!("create_gfx_texture_array: {}", err)))?;
+
+  let data = bmd_texture_array_from_vfs(vfs, &plan.bmd_paths, &plan.shadow_paths, &plan.palette_paths, &plan.bmd_frame_instance_count, &plan.frame_palette_index, "create_gfx_texture_array")?;
+
+  Ok(GfxTextureArray { data, lookup: plan.lookup })
+}
+
+/// Glyph quads for `text` as `(glyph, x, y, width, height)`, wrapped at
+/// `max_width` pixels unless it is 0. `glyph` indexes `Font.glyphMetrics`.
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+pub fn layout_text(text: &str, font: &Font, max_width: usize) -> Box<[f32]> {
+  font::layout_text(text, font, max_width).iter()
+    .flat_map(|q| vec![q.glyph as f32, q.x as f32, q.y as f32, q.width as f32, q.height as f32])
+    .collect::<Vec<_>>()
+    .into_boxed_slice()
 }
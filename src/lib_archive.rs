@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+
+use wasm_bindgen::prelude::*;
+
+use crate::codepage;
+use crate::reader::Reader;
+use crate::vfs::{glob_match, normalize_path};
+
+/// A file stored in a `.lib` archive. `path` keeps the spelling of the
+/// directory; lookups go through `normalize_path`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibEntry {
+  pub path: String,
+  pub offset: usize,
+  pub size: usize,
+}
+
+/// Directory of a game data archive over the archive bytes.
+///
+/// Layout, all integers u32 little endian:
+///
+/// ```text
+/// version, unknown
+/// dir count, then per directory:  name length, name, unknown
+/// file count, then per file:      path length, path, offset, size
+/// ```
+///
+/// Paths are backslash separated and relative to the game's data directory;
+/// offsets are absolute within the archive. The directory is parsed once;
+/// `keys[i]` is the normalized path of `entries[i]`.
+#[wasm_bindgen]
+pub struct LibArchive {
+  buf: Vec<u8>,
+  entries: Vec<LibEntry>,
+  keys: Vec<String>,
+  lookup: HashMap<String, usize>,
+}
+
+/// Parses the directory of an archive without touching the file data.
+pub fn read_directory(buf: &[u8]) -> Result<(Vec<String>, Vec<LibEntry>), &'static str> {
+  let mut r = Reader::new(buf, "Archive directory is truncated.");
+  r.u32()?;
+  r.u32()?;
+
+  let dir_count = r.u32()? as usize;
+  let mut dirs = Vec::with_capacity(dir_count.min(buf.len()));
+  for _ in 0..dir_count {
+    dirs.push(r.string()?);
+    r.u32()?;
+  }
+
+  let file_count = r.u32()? as usize;
+  let mut entries = Vec::with_capacity(file_count.min(buf.len()));
+  for _ in 0..file_count {
+    let path = r.string()?;
+    let offset = r.u32()? as usize;
+    let size = r.u32()? as usize;
+
+    if offset.checked_add(size).is_none_or(|end| end > buf.len()) {
+      return Err("Archive entry points past the end of the archive.");
+    }
+
+    entries.push(LibEntry { path, offset, size });
+  }
+
+  Ok((dirs, entries))
+}
+
+impl LibArchive {
+  pub fn parse(buf: Vec<u8>) -> Result<LibArchive, &'static str> {
+    let (_, entries) = read_directory(&buf)?;
+    let keys: Vec<String> = entries.iter().map(|e| normalize_path(&e.path)).collect();
+    let lookup = keys.iter().enumerate().map(|(i, k)| (k.clone(), i)).collect();
+
+    Ok(LibArchive { buf, entries, keys, lookup })
+  }
+
+  pub fn list(&self) -> &[LibEntry] {
+    &self.entries
+  }
+
+  /// Normalized paths, in directory order.
+  pub fn keys(&self) -> &[String] {
+    &self.keys
+  }
+
+  pub fn entry(&self, path: &str) -> Option<&LibEntry> {
+    self.lookup.get(&normalize_path(path)).map(|&i| &self.entries[i])
+  }
+
+  /// Contents of the file at `path`, compared case-insensitively.
+  pub fn open(&self, path: &str) -> Option<&[u8]> {
+    self.entry(path).map(|e| &self.buf[e.offset..e.offset + e.size])
+  }
+
+  /// Entries whose path matches `pattern`, see `glob_match`, in directory
+  /// order.
+  pub fn glob(&self, pattern: &str) -> Vec<&LibEntry> {
+    let pattern = normalize_path(pattern);
+    self.entries.iter().zip(self.keys.iter()).filter(|(_, k)| glob_match(&pattern, k)).map(|(e, _)| e).collect()
+  }
+}
+
+#[wasm_bindgen]
+impl LibArchive {
+  #[wasm_bindgen(constructor)]
+  pub fn js_new(data: Box<[u8]>) -> Result<LibArchive, JsValue> {
+    LibArchive::parse(data.into_vec()).map_err(JsValue::from_str)
+  }
+
+  /// Paths of all files, spelled as in the directory.
+  #[wasm_bindgen(js_name = list)]
+  pub fn js_list(&self) -> Box<[JsValue]> {
+    self.entries.iter().map(|e| JsValue::from_str(&e.path)).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  /// Paths matching `pattern`, where `*` matches within a path component
+  /// and `**` across components.
+  #[wasm_bindgen(js_name = glob)]
+  pub fn js_glob(&self, pattern: &str) -> Box<[JsValue]> {
+    self.glob(pattern).iter().map(|e| JsValue::from_str(&e.path)).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = open)]
+  pub fn js_open(&self, path: &str) -> Result<Box<[u8]>, JsValue> {
+    self.open(path).map(|data| data.into()).ok_or_else(|| JsValue::from_str(&format!("LibArchive.open: file not found: {}", path)))
+  }
+}
+
+/// Collects files and writes them out as a `.lib` archive in the layout read
+/// by `LibArchive`, directory first and file data in insertion order.
+#[derive(Default)]
+pub struct LibBuilder {
+  files: Vec<(String, Vec<u8>)>,
+  lookup: HashMap<String, usize>,
+}
+
+fn push_u32(out: &mut Vec<u8>, val: usize) -> Result<(), &'static str> {
+  if val > u32::MAX as usize {
+    return Err("Archive is larger than 4 GiB.");
+  }
+
+  out.extend_from_slice(&(val as u32).to_le_bytes());
+  Ok(())
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) -> Result<(), &'static str> {
+  push_u32(out, s.chars().count())?;
+  codepage::encode_into(out, s, "Archive paths must be Windows-1252 characters.")
+}
+
+impl LibBuilder {
+  pub fn new() -> LibBuilder {
+    LibBuilder::default()
+  }
+
+  /// Adds a file, replacing an earlier one with the same normalized path.
+  /// Forward slashes become backslashes; the case is kept.
+  pub fn add(&mut self, path: &str, data: Vec<u8>) {
+    let path: String = path.trim_start_matches(['\\', '/']).replace('/', "\\");
+    let key = normalize_path(&path);
+
+    match self.lookup.get(&key) {
+      Some(&i) => self.files[i] = (path, data),
+      None => {
+        self.lookup.insert(key, self.files.len());
+        self.files.push((path, data));
+      },
+    }
+  }
+
+  /// Every directory containing a file, each ending in a backslash, parents
+  /// before children.
+  fn dirs(&self) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut dirs: Vec<(String, String)> = vec![];
+
+    for (path, _) in self.files.iter() {
+      for (i, _) in path.match_indices('\\') {
+        let dir = &path[..=i];
+        let key = normalize_path(dir);
+        if seen.insert(key.clone()) {
+          dirs.push((key, dir.to_string()));
+        }
+      }
+    }
+
+    dirs.sort();
+    dirs.into_iter().map(|(_, dir)| dir).collect()
+  }
+
+  pub fn build(&self) -> Result<Vec<u8>, &'static str> {
+    let dirs = self.dirs();
+
+    let mut out = vec![];
+    push_u32(&mut out, 1)?;
+    push_u32(&mut out, 0)?;
+
+    push_u32(&mut out, dirs.len())?;
+    for dir in dirs.iter() {
+      push_string(&mut out, dir)?;
+      push_u32(&mut out, 0)?;
+    }
+
+    push_u32(&mut out, self.files.len())?;
+    let directory_len = out.len() + self.files.iter().map(|(p, _)| 12 + p.chars().count()).sum::<usize>();
+
+    let mut offset = directory_len;
+    for (path, data) in self.files.iter() {
+      push_string(&mut out, path)?;
+      push_u32(&mut out, offset)?;
+      push_u32(&mut out, data.len())?;
+      offset += data.len();
+    }
+
+    for (_, data) in self.files.iter() {
+      out.extend_from_slice(data);
+    }
+    if out.len() > u32::MAX as usize {
+      return Err("Archive is larger than 4 GiB.");
+    }
+
+    Ok(out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = LibBuilder::new();
+    for (path, data) in files {
+      builder.add(path, data.to_vec());
+    }
+    builder.build().unwrap()
+  }
+
+  #[test]
+  fn test_lib_archive_lookup() {
+    let buf = archive(&[
+      ("data\\engine2d\\bin\\palettes\\landscapes\\tree01.pcx", b"palette"),
+      ("data\\engine2d\\bin\\bobs\\ls_trees.bmd", b"trees"),
+      ("Data\\Engine2D\\Bin\\Bobs\\ls_rocks.bmd", b"rocks"),
+    ]);
+    let lib = LibArchive::parse(buf).unwrap();
+
+    assert_eq!(lib.list().len(), 3);
+    assert_eq!(lib.open("DATA/engine2d/bin/bobs/LS_TREES.bmd"), Some(&b"trees"[..]));
+    assert_eq!(lib.open("\\data\\engine2d\\bin\\bobs\\ls_rocks.bmd"), Some(&b"rocks"[..]));
+    assert_eq!(lib.open("data\\engine2d\\bin\\bobs\\missing.bmd"), None);
+
+    let bobs: Vec<&str> = lib.glob("data\\engine2d\\bin\\bobs\\ls_*.bmd").iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(bobs.len(), 2);
+    assert_eq!(lib.glob("data\\*.pcx").len(), 0);
+    assert_eq!(lib.glob("data\\**.pcx").len(), 1);
+  }
+
+  #[test]
+  fn test_lib_archive_errors() {
+    let mut buf = archive(&[("a.pcx", b"abc")]);
+    assert!(LibArchive::parse(buf[..20].to_vec()).is_err());
+
+    buf.truncate(buf.len() - 1);
+    assert!(LibArchive::parse(buf).is_err());
+  }
+
+  #[test]
+  fn test_lib_builder_round_trip() {
+    let palette: Vec<u8> = (0..768).map(|i| (i % 256) as u8).collect();
+    let pcx = crate::pcx::pcx_write(4, 2, &[0, 1, 2, 200, 200, 200, 7, 7], &palette).unwrap();
+
+    let mut builder = LibBuilder::new();
+    builder.add("data/engine2d/bin/textures/gras.pcx", pcx.clone());
+    builder.add("Data\\Mods\\readme.txt", b"first".to_vec());
+    builder.add("data\\mods\\README.TXT", b"second".to_vec());
+    builder.add("top.txt", vec![]);
+    let buf = builder.build().unwrap();
+
+    let (dirs, entries) = read_directory(&buf).unwrap();
+    assert_eq!(dirs, vec!["data\\", "data\\engine2d\\", "data\\engine2d\\bin\\", "data\\engine2d\\bin\\textures\\", "data\\mods\\"]);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].path, "data\\engine2d\\bin\\textures\\gras.pcx");
+
+    let lib = LibArchive::parse(buf).unwrap();
+    assert_eq!(lib.open("data\\engine2d\\bin\\textures\\gras.pcx"), Some(&pcx[..]));
+    assert_eq!(lib.open("data/mods/readme.txt"), Some(&b"second"[..]));
+    assert_eq!(lib.open("top.txt"), Some(&b""[..]));
+    assert_eq!(crate::pcx::get_dimensions(lib.open("data/engine2d/bin/textures/gras.pcx").unwrap()), Ok((4, 2)));
+  }
+}
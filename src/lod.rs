@@ -0,0 +1,361 @@
+use crate::tessellate::{self, Chunk, TessellationOptions};
+
+/// Coarsest level usable with `chunk_size`: level `l` steps `2^l` samples, and
+/// chunk corners have to line up on every level.
+pub fn max_level(chunk_size: usize) -> usize {
+  chunk_size.trailing_zeros().min(15) as usize
+}
+
+/// Samples covered by `chunk`. LOD meshes span sample columns `x..=x + width`
+/// and rows `y..=y + height`, so neighbouring chunks share their edge samples.
+/// The half cells left of sample column 0 are not part of any level.
+fn sample_range(chunk: &Chunk) -> ((usize, usize), (usize, usize)) {
+  ((chunk.x, chunk.x + chunk.width), (chunk.y, chunk.y + chunk.height))
+}
+
+/// Step of `level` within `chunk`. Chunks only one sample wide or high at
+/// the map edge stay at full detail.
+fn chunk_step(chunk: &Chunk, level: usize) -> usize {
+  if chunk.width < 2 || chunk.height < 2 { 1 } else { 1 << level }
+}
+
+/// Quad corners from `start` to `end` every `step` samples. A last gap of a
+/// single sample is merged into the quad before it, so coarse quads always
+/// have an interior centre.
+fn stops(start: usize, end: usize, step: usize) -> Vec<usize> {
+  let mut stops: Vec<usize> = (start..end).step_by(step).collect();
+  stops.push(end);
+
+  if step > 1 && stops.len() > 2 && end - stops[stops.len() - 2] == 1 {
+    stops.remove(stops.len() - 2);
+  }
+
+  stops
+}
+
+#[inline]
+fn vertex(width: usize, sx: usize, sy: usize) -> u32 {
+  (sy * tessellate::vertex_grid_width(width) + sx + 1) as u32
+}
+
+/// Centre sample of a coarse quad.
+#[inline]
+fn quad_center(x: (usize, usize), y: (usize, usize)) -> (usize, usize) {
+  ((x.0 + x.1) / 2, (y.0 + y.1) / 2)
+}
+
+/// Full detail quad between samples `(x, y)` and `(x + 1, y + 1)`, made of the
+/// same triangles as `triangulate_map_indices`.
+fn push_fine_quad(out: &mut Vec<u32>, width: usize, x: usize, y: usize) {
+  let v = |sx, sy| vertex(width, sx, sy);
+
+  if y.is_multiple_of(2) {
+    out.extend_from_slice(&[v(x, y), v(x + 1, y), v(x, y + 1)]);
+    out.extend_from_slice(&[v(x + 1, y), v(x + 1, y + 1), v(x, y + 1)]);
+  } else {
+    out.extend_from_slice(&[v(x, y), v(x + 1, y + 1), v(x, y + 1)]);
+    out.extend_from_slice(&[v(x, y), v(x + 1, y), v(x + 1, y + 1)]);
+  }
+}
+
+/// Steps the four edges of a chunk are subdivided at: top, right, bottom,
+/// left. Edges facing a finer neighbour take the neighbour's step so both
+/// sides share every vertex.
+type EdgeSteps = [usize; 4];
+
+/// Ground position of sample `(sx, sy)` in half cell units, exact for the
+/// orientation tests below.
+#[inline]
+fn ground(s: (usize, usize)) -> (i64, i64) {
+  (2 * s.0 as i64 + (s.1 % 2) as i64, 2 * s.1 as i64)
+}
+
+/// Twice the signed area of `abc`, positive for the winding of
+/// `triangulate_map`.
+#[inline]
+fn orient(a: (usize, usize), b: (usize, usize), c: (usize, usize)) -> i64 {
+  let (a, b, c) = (ground(a), ground(b), ground(c));
+  (b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)
+}
+
+/// Coarse quad with each edge split at `edges[k]` samples. Usually a fan
+/// around the centre sample; edges stitched to full detail neighbours zigzag
+/// with the odd rows, and when the centre cannot see all of such an edge the
+/// outline is ear-clipped instead.
+fn push_coarse_quad(out: &mut Vec<u32>, width: usize, x: (usize, usize), y: (usize, usize), edges: &EdgeSteps) {
+  let top = stops(x.0, x.1, edges[0]).into_iter().map(|sx| (sx, y.0));
+  let right = stops(y.0, y.1, edges[1]).into_iter().map(|sy| (x.1, sy));
+  let bottom = stops(x.0, x.1, edges[2]).into_iter().rev().map(|sx| (sx, y.1));
+  let left = stops(y.0, y.1, edges[3]).into_iter().rev().map(|sy| (x.0, sy));
+
+  // Clockwise on screen, each corner once.
+  let mut outline = vec![];
+  for edge in [top.collect::<Vec<_>>(), right.collect(), bottom.collect(), left.collect()].iter() {
+    outline.extend_from_slice(&edge[..edge.len() - 1]);
+  }
+
+  let v = |s: (usize, usize)| vertex(width, s.0, s.1);
+  let n = outline.len();
+  let c = quad_center(x, y);
+
+  if (0..n).all(|k| orient(c, outline[k], outline[(k + 1) % n]) > 0) {
+    for k in 0..n {
+      out.extend_from_slice(&[v(c), v(outline[k]), v(outline[(k + 1) % n])]);
+    }
+    return;
+  }
+
+  while outline.len() > 3 {
+    let n = outline.len();
+    let ear = (0..n).find(|&k| {
+      let (a, b, c) = (outline[(k + n - 1) % n], outline[k], outline[(k + 1) % n]);
+
+      orient(a, b, c) > 0 && outline.iter().all(|&p| {
+        p == a || p == b || p == c || orient(a, b, p) < 0 || orient(b, c, p) < 0 || orient(c, a, p) < 0
+      })
+    });
+
+    match ear {
+      Some(k) => {
+        out.extend_from_slice(&[v(outline[(k + n - 1) % n]), v(outline[k]), v(outline[(k + 1) % n])]);
+        outline.remove(k);
+      },
+      None => break,
+    }
+  }
+
+  if outline.len() == 3 {
+    out.extend_from_slice(&[v(outline[0]), v(outline[1]), v(outline[2])]);
+  }
+}
+
+/// Neighbour of chunk `(cx, cy)` in direction `d` (top, right, bottom, left).
+fn neighbour(cols: usize, rows: usize, cx: usize, cy: usize, d: usize) -> Option<usize> {
+  match d {
+    0 if cy > 0 => Some((cy - 1) * cols + cx),
+    1 if cx + 1 < cols => Some(cy * cols + cx + 1),
+    2 if cy + 1 < rows => Some((cy + 1) * cols + cx),
+    3 if cx > 0 => Some(cy * cols + cx - 1),
+    _ => None,
+  }
+}
+
+/// Indices into `triangulate_map_vertices` for chunk `(cx, cy)` at
+/// `levels[cy * cols + cx]`, stitched to the levels of its four neighbours.
+/// `levels` holds one level per chunk in row-major order.
+pub fn triangulate_chunk_lod(width: usize, height: usize, chunk_size: usize, cx: usize, cy: usize, levels: &[u8]) -> Result<Vec<u32>, &'static str> {
+  let (cols, rows) = tessellate::chunk_grid(width, height, chunk_size);
+  let chunk = tessellate::chunk_at(width, height, chunk_size, cx, cy).ok_or("Chunk is outside the map.")?;
+
+  if levels.len() < cols * rows {
+    return Err("Need one level per chunk.");
+  }
+  if levels.iter().any(|l| *l as usize > max_level(chunk_size)) {
+    return Err("Level is too coarse for the chunk size.");
+  }
+
+  let step_of = |c: usize| chunk_step(&tessellate::chunk_at(width, height, chunk_size, c % cols, c / cols).unwrap(), levels[c] as usize);
+  let step = step_of(cy * cols + cx);
+  let ((x0, x1), (y0, y1)) = sample_range(&chunk);
+  let mut out = vec![];
+
+  if step == 1 {
+    for y in y0..y1 {
+      for x in x0..x1 {
+        push_fine_quad(&mut out, width, x, y);
+      }
+    }
+    return Ok(out);
+  }
+
+  let mut outer = [step; 4];
+  for (d, s) in outer.iter_mut().enumerate() {
+    if let Some(n) = neighbour(cols, rows, cx, cy, d) {
+      *s = step_of(n).min(step);
+    }
+  }
+
+  let xs = stops(x0, x1, step);
+  let ys = stops(y0, y1, step);
+
+  for (j, y) in ys.windows(2).enumerate() {
+    for (i, x) in xs.windows(2).enumerate() {
+      let edges = [
+        if j == 0 { outer[0] } else { step },
+        if i == xs.len() - 2 { outer[1] } else { step },
+        if j == ys.len() - 2 { outer[2] } else { step },
+        if i == 0 { outer[3] } else { step },
+      ];
+      push_coarse_quad(&mut out, width, (x[0], x[1]), (y[0], y[1]), &edges);
+    }
+  }
+
+  Ok(out)
+}
+
+/// Height of the fan of the coarse quad above the ground position of sample
+/// `s`, or `None` if the sample lies outside the fan.
+fn fan_height(heights: &[f32], width: usize, x: (usize, usize), y: (usize, usize), s: (usize, usize)) -> Option<f32> {
+  const EPSILON: f32 = -1e-5;
+
+  let c = quad_center(x, y);
+  let corners = [(x.0, y.0), (x.1, y.0), (x.1, y.1), (x.0, y.1)];
+  let h = |s: (usize, usize)| heights[vertex(width, s.0, s.1) as usize];
+  let f = |s: (usize, usize)| {
+    let g = ground(s);
+    (g.0 as f32, g.1 as f32)
+  };
+
+  for k in 0..4 {
+    let (a, b) = (corners[k], corners[(k + 1) % 4]);
+    if let Some(w) = tessellate::barycentric(f(s), f(c), f(a), f(b)) {
+      if w.iter().all(|w| *w >= EPSILON) {
+        return Some(w[0] * h(c) + w[1] * h(a) + w[2] * h(b));
+      }
+    }
+  }
+
+  None
+}
+
+/// Largest height difference between the samples of every chunk and its
+/// mesh at each level, `max_level(chunk_size) + 1` values per chunk in
+/// row-major chunk order. Errors are measured at the staggered ground
+/// positions of `triangulate_map` against the unstitched fans, and never
+/// shrink with increasing level. Samples that the stagger pushes out of a
+/// fan lie in the neighbouring quad and are measured there.
+pub fn chunk_lod_errors(width: usize, height: usize, elevation: &[u8], chunk_size: usize, options: &TessellationOptions) -> Vec<f32> {
+  let heights = tessellate::vertex_heights(width, height, elevation, options);
+  let (cols, rows) = tessellate::chunk_grid(width, height, chunk_size);
+  let levels = max_level(chunk_size) + 1;
+  let mut errors = vec![0.0f32; cols * rows * levels];
+
+  for (c, r) in errors.chunks_mut(levels).enumerate() {
+    let chunk = tessellate::chunk_at(width, height, chunk_size, c % cols, c / cols).unwrap();
+    let ((x0, x1), (y0, y1)) = sample_range(&chunk);
+
+    for level in 1..levels {
+      let step = chunk_step(&chunk, level);
+      let mut error = r[level - 1];
+
+      if step > 1 {
+        let xs = stops(x0, x1, step);
+        let ys = stops(y0, y1, step);
+
+        for y in ys.windows(2) {
+          for x in xs.windows(2) {
+            for sy in y[0]..=y[1] {
+              for sx in x[0]..=x[1] {
+                if let Some(approx) = fan_height(&heights, width, (x[0], x[1]), (y[0], y[1]), (sx, sy)) {
+                  error = error.max((heights[vertex(width, sx, sy) as usize] - approx).abs());
+                }
+              }
+            }
+          }
+        }
+      }
+
+      r[level] = error;
+    }
+  }
+
+  errors
+}
+
+/// Coarsest level of every chunk whose error stays within `max_error`, from
+/// the output of `chunk_lod_errors`.
+pub fn select_levels(errors: &[f32], chunk_size: usize, max_error: f32) -> Vec<u8> {
+  errors.chunks(max_level(chunk_size) + 1)
+    .map(|e| e.iter().rposition(|e| *e <= max_error).unwrap_or(0) as u8)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tessellate::BorderMode;
+
+  fn area(vertices: &[f32], tri: &[u32]) -> f32 {
+    let p = |k: usize| (vertices[2 * tri[k] as usize], vertices[2 * tri[k] as usize + 1]);
+    let (a, b, c) = (p(0), p(1), p(2));
+    ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)) / 2.0
+  }
+
+  /// Edges used by exactly one triangle that do not run along the map
+  /// outline, i.e. cracks.
+  fn inner_open_edges(indices: &[u32], w: usize, h: usize) -> usize {
+    let mut count = std::collections::HashMap::new();
+    for tri in indices.chunks(3) {
+      for k in 0..3 {
+        let (a, b) = (tri[k], tri[(k + 1) % 3]);
+        *count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+      }
+    }
+
+    let vw = tessellate::vertex_grid_width(w) as u32;
+    let sample = |v: u32| (v % vw - 1, v / vw);
+    let outline = |a: (u32, u32), b: (u32, u32)| {
+      (a.0 == 0 && b.0 == 0) || (a.0 == w as u32 && b.0 == w as u32) || (a.1 == 0 && b.1 == 0) || (a.1 == h as u32 && b.1 == h as u32)
+    };
+
+    count.into_iter().filter(|((a, b), n)| *n == 1 && !outline(sample(*a), sample(*b))).count()
+  }
+
+  #[test]
+  fn test_lod_levels_are_crack_free() {
+    let (w, h, chunk_size) = (19, 13, 8);
+    let elevation = vec![0u8; w * h];
+    let options = TessellationOptions::default();
+    let vertices = tessellate::triangulate_map_vertices(w, h, &elevation, &options);
+    let (cols, rows) = tessellate::chunk_grid(w, h, chunk_size);
+
+    let full: Vec<u32> = (0..cols * rows)
+      .flat_map(|c| triangulate_chunk_lod(w, h, chunk_size, c % cols, c / cols, &vec![0; cols * rows]).unwrap())
+      .collect();
+
+    // Every combination of levels for the 3x2 chunks.
+    for combination in 0..4usize.pow(6) {
+      let levels: Vec<u8> = (0..6).map(|c| (combination >> (2 * c) & 3) as u8).collect();
+      let mesh: Vec<u32> = (0..cols * rows)
+        .flat_map(|c| triangulate_chunk_lod(w, h, chunk_size, c % cols, c / cols, &levels).unwrap())
+        .collect();
+
+      // No cracks, same winding, and no triangles folding over each other.
+      assert_eq!(inner_open_edges(&mesh, w, h), 0);
+      assert!(mesh.chunks(3).all(|t| area(&vertices, t) > 0.0));
+
+      let mut directed: Vec<(u32, u32)> = mesh.chunks(3).flat_map(|t| vec![(t[0], t[1]), (t[1], t[2]), (t[2], t[0])]).collect();
+      let edges = directed.len();
+      directed.sort();
+      directed.dedup();
+      assert_eq!(directed.len(), edges);
+      assert!(mesh.len() <= full.len());
+    }
+  }
+
+  #[test]
+  fn test_lod_errors() {
+    let (w, h, chunk_size) = (16, 16, 8);
+    let options = TessellationOptions { border_mode: BorderMode::Clamp, ..TessellationOptions::default() };
+
+    // Flat ground is exact at every level.
+    let flat = vec![64u8; w * h];
+    let errors = chunk_lod_errors(w, h, &flat, chunk_size, &options);
+    assert!(errors.iter().all(|e| *e < 1e-4));
+    assert_eq!(select_levels(&errors, chunk_size, 0.1), vec![3; 4]);
+
+    // A single peak forces its chunk to full detail.
+    let mut peak = vec![0u8; w * h];
+    peak[3 * w + 2] = 160;
+    let errors = chunk_lod_errors(w, h, &peak, chunk_size, &options);
+    let levels = select_levels(&errors, chunk_size, 1.0);
+    assert_eq!(levels[0], 0);
+    assert_eq!(levels[3], 3);
+
+    // Heights that rise with the ground x position, odd rows half a cell
+    // further right, form a plane and need no detail.
+    let plane: Vec<u8> = (0..w * h).map(|i| (8 * (2 * (i % w) + (i / w) % 2)) as u8).collect();
+    let errors = chunk_lod_errors(w, h, &plane, chunk_size, &options);
+    assert!(errors[..max_level(chunk_size) + 1].iter().all(|e| *e < 1e-4), "{:?}", errors);
+  }
+}
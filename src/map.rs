@@ -0,0 +1,623 @@
+use wasm_bindgen::prelude::*;
+
+use crate::codepage;
+use crate::heightfield::Heightfield;
+use crate::reader::Reader;
+use crate::tessellate::{self, TessellationOptions};
+
+/// Section tags are two little endian four character words, `hoix` and the
+/// reversed section id, so `lsiz` is stored as `hoixzisl`.
+pub const TAG_SIZE: &[u8; 8] = b"hoixzisl";
+pub const TAG_INFO: &[u8; 8] = b"hoixofni";
+pub const TAG_ELEVATION: &[u8; 8] = b"hoixiehm";
+pub const TAG_TYPES_A: &[u8; 8] = b"hoix1tlm";
+pub const TAG_TYPES_B: &[u8; 8] = b"hoix2tlm";
+pub const TAG_OBJECTS: &[u8; 8] = b"hoixjbom";
+pub const TAG_RESOURCES: &[u8; 8] = b"hoixserm";
+pub const TAG_START: &[u8; 8] = b"hoixtrts";
+
+/// One tagged section of a map file with its raw contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapSection {
+  pub tag: [u8; 8],
+  pub version: u32,
+  pub data: Vec<u8>,
+}
+
+/// A per-cell layer, row-major with `width * height` values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Grid<T> {
+  pub width: usize,
+  pub height: usize,
+  pub data: Vec<T>,
+}
+
+impl<T: Copy + Default> Grid<T> {
+  pub fn new(width: usize, height: usize) -> Grid<T> {
+    Grid { width, height, data: vec![T::default(); width * height] }
+  }
+
+  pub fn from_data(width: usize, height: usize, data: Vec<T>) -> Result<Grid<T>, &'static str> {
+    if data.len() != width * height {
+      return Err("Map layer size does not match the map.");
+    }
+
+    Ok(Grid { width, height, data })
+  }
+
+  pub fn get(&self, x: usize, y: usize) -> Option<T> {
+    if x < self.width && y < self.height { Some(self.data[y * self.width + x]) } else { None }
+  }
+}
+
+/// A landscape object placed on the map, named as in the graphics
+/// definitions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapObject {
+  pub x: u32,
+  pub y: u32,
+  pub name: String,
+}
+
+/// A map with its decoded layers. Missing optional sections read as empty
+/// layers.
+#[wasm_bindgen]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Map {
+  #[wasm_bindgen(skip)]
+  pub name: String,
+  #[wasm_bindgen(skip)]
+  pub player_count: u32,
+  #[wasm_bindgen(skip)]
+  pub elevation: Grid<u8>,
+  #[wasm_bindgen(skip)]
+  pub types_a: Grid<u8>,
+  #[wasm_bindgen(skip)]
+  pub types_b: Grid<u8>,
+  #[wasm_bindgen(skip)]
+  pub objects: Vec<MapObject>,
+  #[wasm_bindgen(skip)]
+  pub resource_types: Grid<u8>,
+  #[wasm_bindgen(skip)]
+  pub resource_amounts: Grid<u8>,
+  /// `(x, y)` per player.
+  #[wasm_bindgen(skip)]
+  pub start_positions: Vec<(u32, u32)>,
+  /// Every section in file order, decoded ones included. `write_map` keeps
+  /// the others byte for byte.
+  #[wasm_bindgen(skip)]
+  pub sections: Vec<MapSection>,
+}
+
+/// Splits a map file into its sections. Each is an 8 byte tag, u32 version
+/// and u32 byte size, little endian, followed by the contents.
+pub fn read_sections(buf: &[u8]) -> Result<Vec<MapSection>, &'static str> {
+  let mut r = Reader::new(buf, "Map section is truncated.");
+  let mut sections = vec![];
+
+  while !r.is_empty() {
+    let mut tag = [0u8; 8];
+    tag.copy_from_slice(r.bytes(8)?);
+    let version = r.u32()?;
+    let size = r.u32()? as usize;
+
+    sections.push(MapSection { tag, version, data: r.bytes(size)?.to_vec() });
+  }
+
+  Ok(sections)
+}
+
+/// Parses a map file. Known sections and their contents:
+///
+/// ```text
+/// lsiz  width, height
+/// info  player count, name (u32 length and bytes)
+/// mhei  elevation, one byte per cell
+/// mlt1  landscape type of the first triangle of each cell
+/// mlt2  landscape type of the second triangle
+/// mobj  count, then per object x, y, name
+/// mres  resource type per cell, then resource amount per cell
+/// strt  count, then per player x, y
+/// ```
+///
+/// Integers are u32 little endian and layers are row-major. `lsiz` and
+/// `mhei` are required; other sections are kept undecoded.
+pub fn read_map(buf: &[u8]) -> Result<Map, &'static str> {
+  let sections = read_sections(buf)?;
+  let find = |tag: &[u8; 8]| sections.iter().find(|s| &s.tag == tag).map(|s| &s.data[..]);
+
+  let mut r = Reader::new(find(TAG_SIZE).ok_or("Map has no size section.")?, "Map section is truncated.");
+  let width = r.u32()? as usize;
+  let height = r.u32()? as usize;
+  let cells = width.checked_mul(height).ok_or("Map size is too large.")?;
+
+  let layer = |tag: &[u8; 8]| -> Result<Grid<u8>, &'static str> {
+    match find(tag) {
+      Some(data) => Grid::from_data(width, height, data.to_vec()),
+      None => Ok(Grid::new(width, height)),
+    }
+  };
+
+  let elevation = Grid::from_data(width, height, find(TAG_ELEVATION).ok_or("Map has no elevation section.")?.to_vec())?;
+
+  let (name, player_count) = match find(TAG_INFO) {
+    Some(data) => {
+      let mut r = Reader::new(data, "Map section is truncated.");
+      let player_count = r.u32()?;
+      (r.string()?, player_count)
+    },
+    None => (String::new(), 0),
+  };
+
+  let mut objects = vec![];
+  if let Some(data) = find(TAG_OBJECTS) {
+    let mut r = Reader::new(data, "Map section is truncated.");
+    for _ in 0..r.u32()? {
+      objects.push(MapObject { x: r.u32()?, y: r.u32()?, name: r.string()? });
+    }
+  }
+
+  let (resource_types, resource_amounts) = match find(TAG_RESOURCES) {
+    Some(data) if data.len() == 2 * cells => (Grid::from_data(width, height, data[..cells].to_vec())?, Grid::from_data(width, height, data[cells..].to_vec())?),
+    Some(_) => return Err("Map resource section size does not match the map."),
+    None => (Grid::new(width, height), Grid::new(width, height)),
+  };
+
+  let mut start_positions = vec![];
+  if let Some(data) = find(TAG_START) {
+    let mut r = Reader::new(data, "Map section is truncated.");
+    for _ in 0..r.u32()? {
+      start_positions.push((r.u32()?, r.u32()?));
+    }
+  }
+
+  Ok(Map {
+    name,
+    player_count,
+    elevation,
+    types_a: layer(TAG_TYPES_A)?,
+    types_b: layer(TAG_TYPES_B)?,
+    objects,
+    resource_types,
+    resource_amounts,
+    start_positions,
+    sections,
+  })
+}
+
+fn push_u32(out: &mut Vec<u8>, val: usize) -> Result<(), &'static str> {
+  if val > u32::MAX as usize {
+    return Err("Map section value does not fit in 32 bits.");
+  }
+
+  out.extend_from_slice(&(val as u32).to_le_bytes());
+  Ok(())
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) -> Result<(), &'static str> {
+  push_u32(out, s.chars().count())?;
+  codepage::encode_into(out, s, "Map strings must be Windows-1252 characters.")
+}
+
+/// Version `write_map` gives a section the input did not have.
+pub const SECTION_VERSION: u32 = 1;
+
+/// Tags `write_map` encodes from the typed layers, in the order it adds the
+/// ones the input did not have.
+const KNOWN_TAGS: [&[u8; 8]; 8] = [TAG_SIZE, TAG_INFO, TAG_ELEVATION, TAG_TYPES_A, TAG_TYPES_B, TAG_OBJECTS, TAG_RESOURCES, TAG_START];
+
+fn encode_section(map: &Map, tag: &[u8; 8]) -> Result<Vec<u8>, &'static str> {
+  let mut out = vec![];
+
+  match tag {
+    TAG_SIZE => {
+      push_u32(&mut out, map.width())?;
+      push_u32(&mut out, map.height())?;
+    },
+    TAG_INFO => {
+      push_u32(&mut out, map.player_count as usize)?;
+      push_string(&mut out, &map.name)?;
+    },
+    TAG_ELEVATION => out.extend_from_slice(&map.elevation.data),
+    TAG_TYPES_A => out.extend_from_slice(&map.types_a.data),
+    TAG_TYPES_B => out.extend_from_slice(&map.types_b.data),
+    TAG_OBJECTS => {
+      push_u32(&mut out, map.objects.len())?;
+      for object in map.objects.iter() {
+        push_u32(&mut out, object.x as usize)?;
+        push_u32(&mut out, object.y as usize)?;
+        push_string(&mut out, &object.name)?;
+      }
+    },
+    TAG_RESOURCES => {
+      out.extend_from_slice(&map.resource_types.data);
+      out.extend_from_slice(&map.resource_amounts.data);
+    },
+    TAG_START => {
+      push_u32(&mut out, map.start_positions.len())?;
+      for &(x, y) in map.start_positions.iter() {
+        push_u32(&mut out, x as usize)?;
+        push_u32(&mut out, y as usize)?;
+      }
+    },
+    _ => return Err("Map section tag is not known."),
+  }
+
+  Ok(out)
+}
+
+/// Whether an optional section would hold nothing beyond what a fresh
+/// `Map::new` has, so leaving it out of the file loses nothing.
+fn section_is_empty(map: &Map, tag: &[u8; 8]) -> bool {
+  let zero = |grid: &Grid<u8>| grid.data.iter().all(|&v| v == 0);
+
+  match tag {
+    TAG_INFO => map.name.is_empty() && map.player_count == 0,
+    TAG_TYPES_A => zero(&map.types_a),
+    TAG_TYPES_B => zero(&map.types_b),
+    TAG_OBJECTS => map.objects.is_empty(),
+    TAG_RESOURCES => zero(&map.resource_types) && zero(&map.resource_amounts),
+    TAG_START => map.start_positions.is_empty(),
+    _ => false,
+  }
+}
+
+/// Encodes a map in the layout read by `read_map`, over the sections it was
+/// read from. Known sections are written from the typed layers where they
+/// were in `map.sections`, keeping their version, and later copies are left
+/// out. Every other section is copied unchanged. Known sections the input
+/// did not have are appended in `KNOWN_TAGS` order with `SECTION_VERSION`,
+/// the optional ones only when they hold data, so a fresh `Map::new` is
+/// written as its size and elevation sections.
+pub fn write_map(map: &Map) -> Result<Vec<u8>, &'static str> {
+  let cells = map.width() * map.height();
+  let layers = [&map.types_a, &map.types_b, &map.resource_types, &map.resource_amounts];
+  if layers.iter().any(|l| l.width != map.width() || l.height != map.height() || l.data.len() != cells) || map.elevation.data.len() != cells {
+    return Err("Map layer size does not match the map.");
+  }
+
+  let mut written: Vec<&[u8; 8]> = vec![];
+  let mut sections: Vec<(&[u8; 8], u32, Vec<u8>)> = vec![];
+
+  for section in map.sections.iter() {
+    match KNOWN_TAGS.iter().find(|&&tag| tag == &section.tag) {
+      Some(&tag) if written.contains(&tag) => {},
+      Some(&tag) => {
+        written.push(tag);
+        sections.push((tag, section.version, encode_section(map, tag)?));
+      },
+      None => sections.push((&section.tag, section.version, section.data.clone())),
+    }
+  }
+
+  for &tag in KNOWN_TAGS.iter() {
+    if !written.contains(&tag) && !section_is_empty(map, tag) {
+      sections.push((tag, SECTION_VERSION, encode_section(map, tag)?));
+    }
+  }
+
+  let mut out = vec![];
+  for (tag, version, data) in sections {
+    out.extend_from_slice(tag);
+    push_u32(&mut out, version as usize)?;
+    push_u32(&mut out, data.len())?;
+    out.extend_from_slice(&data);
+  }
+
+  Ok(out)
+}
+
+impl Map {
+  /// A flat map without objects, players or other sections.
+  pub fn new(width: usize, height: usize) -> Map {
+    Map {
+      name: String::new(),
+      player_count: 0,
+      elevation: Grid::new(width, height),
+      types_a: Grid::new(width, height),
+      types_b: Grid::new(width, height),
+      objects: vec![],
+      resource_types: Grid::new(width, height),
+      resource_amounts: Grid::new(width, height),
+      start_positions: vec![],
+      sections: vec![],
+    }
+  }
+
+  pub fn width(&self) -> usize {
+    self.elevation.width
+  }
+
+  pub fn height(&self) -> usize {
+    self.elevation.height
+  }
+
+  /// The `triangulate` buffer of the map.
+  pub fn triangulate(&self, options: &TessellationOptions) -> Vec<f32> {
+    let mut tris = vec![0.0; self.width() * self.height() * 2 * 2 * 3];
+    tessellate::triangulate_map(&mut tris, self.width(), self.height(), &self.elevation.data, options);
+    tris
+  }
+
+  pub fn heightfield(&self, options: &TessellationOptions) -> Result<Heightfield, &'static str> {
+    Heightfield::from_map(self.width(), self.height(), &self.elevation.data, options)
+  }
+
+  /// `triangulate_map_attributes` over the landscape type layers.
+  pub fn attributes(&self, texture_layer: &[u32], transitions: &[(u8, u8, u32)], options: &TessellationOptions) -> Result<Vec<f32>, &'static str> {
+    tessellate::triangulate_map_attributes(self.width(), self.height(), &self.types_a.data, &self.types_b.data, texture_layer, transitions, options)
+  }
+}
+
+fn js_layer(map: &Map, data: &[u8], caller: &str) -> Result<Grid<u8>, JsValue> {
+  Grid::from_data(map.width(), map.height(), data.to_vec()).map_err(|err| JsValue::from_str(&format!("Map.{}: {}", caller, err)))
+}
+
+#[wasm_bindgen]
+impl Map {
+  #[wasm_bindgen(constructor)]
+  pub fn js_new(width: usize, height: usize) -> Map {
+    Map::new(width, height)
+  }
+
+  #[wasm_bindgen(js_name = read)]
+  pub fn js_read(buf: &[u8]) -> Result<Map, JsValue> {
+    read_map(buf).map_err(|err| JsValue::from_str(&format!("Map.read: {}", err)))
+  }
+
+  #[wasm_bindgen(getter, js_name = width)]
+  pub fn js_width(&self) -> usize {
+    self.width()
+  }
+
+  #[wasm_bindgen(getter, js_name = height)]
+  pub fn js_height(&self) -> usize {
+    self.height()
+  }
+
+  #[wasm_bindgen(getter, js_name = name)]
+  pub fn js_name(&self) -> String {
+    self.name.clone()
+  }
+
+  #[wasm_bindgen(getter, js_name = playerCount)]
+  pub fn js_player_count(&self) -> u32 {
+    self.player_count
+  }
+
+  #[wasm_bindgen(js_name = elevation)]
+  pub fn js_elevation(&self) -> Box<[u8]> {
+    self.elevation.data.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = typesA)]
+  pub fn js_types_a(&self) -> Box<[u8]> {
+    self.types_a.data.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = typesB)]
+  pub fn js_types_b(&self) -> Box<[u8]> {
+    self.types_b.data.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = resourceTypes)]
+  pub fn js_resource_types(&self) -> Box<[u8]> {
+    self.resource_types.data.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = resourceAmounts)]
+  pub fn js_resource_amounts(&self) -> Box<[u8]> {
+    self.resource_amounts.data.clone().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = objectNames)]
+  pub fn js_object_names(&self) -> Box<[JsValue]> {
+    self.objects.iter().map(|o| JsValue::from_str(&o.name)).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  /// `(x, y)` per object, in the order of `objectNames`.
+  #[wasm_bindgen(js_name = objectPositions)]
+  pub fn js_object_positions(&self) -> Box<[u32]> {
+    self.objects.iter().flat_map(|o| vec![o.x, o.y]).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = startPositions)]
+  pub fn js_start_positions(&self) -> Box<[u32]> {
+    self.start_positions.iter().flat_map(|&(x, y)| vec![x, y]).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(setter, js_name = name)]
+  pub fn js_set_name(&mut self, name: String) {
+    self.name = name;
+  }
+
+  #[wasm_bindgen(setter, js_name = playerCount)]
+  pub fn js_set_player_count(&mut self, player_count: u32) {
+    self.player_count = player_count;
+  }
+
+  #[wasm_bindgen(js_name = setElevation)]
+  pub fn js_set_elevation(&mut self, elevation: &[u8]) -> Result<(), JsValue> {
+    self.elevation = js_layer(self, elevation, "setElevation")?;
+    Ok(())
+  }
+
+  #[wasm_bindgen(js_name = setTypes)]
+  pub fn js_set_types(&mut self, types_a: &[u8], types_b: &[u8]) -> Result<(), JsValue> {
+    self.types_a = js_layer(self, types_a, "setTypes")?;
+    self.types_b = js_layer(self, types_b, "setTypes")?;
+    Ok(())
+  }
+
+  #[wasm_bindgen(js_name = setResources)]
+  pub fn js_set_resources(&mut self, types: &[u8], amounts: &[u8]) -> Result<(), JsValue> {
+    self.resource_types = js_layer(self, types, "setResources")?;
+    self.resource_amounts = js_layer(self, amounts, "setResources")?;
+    Ok(())
+  }
+
+  /// Replaces the objects; `positions` holds `(x, y)` per name.
+  #[wasm_bindgen(js_name = setObjects)]
+  pub fn js_set_objects(&mut self, names: Box<[JsValue]>, positions: &[u32]) -> Result<(), JsValue> {
+    if positions.len() != 2 * names.len() {
+      return Err(JsValue::from_str("Map.setObjects: need one position per name."));
+    }
+
+    let mut objects = Vec::with_capacity(names.len());
+    for (name, p) in names.into_vec().into_iter().zip(positions.chunks(2)) {
+      let name = name.as_string().ok_or_else(|| JsValue::from_str("Map.setObjects: names must be strings."))?;
+      objects.push(MapObject { x: p[0], y: p[1], name });
+    }
+
+    self.objects = objects;
+    Ok(())
+  }
+
+  /// `positions` holds `(x, y)` per player.
+  #[wasm_bindgen(js_name = setStartPositions)]
+  pub fn js_set_start_positions(&mut self, positions: &[u32]) -> Result<(), JsValue> {
+    if !positions.len().is_multiple_of(2) {
+      return Err(JsValue::from_str("Map.setStartPositions: positions must be (x, y) pairs."));
+    }
+
+    self.start_positions = positions.chunks(2).map(|p| (p[0], p[1])).collect();
+    Ok(())
+  }
+
+  #[wasm_bindgen(js_name = write)]
+  pub fn js_write(&self) -> Result<Box<[u8]>, JsValue> {
+    write_map(self).map(|out| out.into_boxed_slice()).map_err(|err| JsValue::from_str(&format!("Map.write: {}", err)))
+  }
+
+  #[wasm_bindgen(js_name = triangulate)]
+  pub fn js_triangulate(&self, options: &TessellationOptions) -> Box<[f32]> {
+    self.triangulate(options).into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = heightfield)]
+  pub fn js_heightfield(&self, options: &TessellationOptions) -> Result<Heightfield, JsValue> {
+    self.heightfield(options).map_err(JsValue::from_str)
+  }
+
+  /// `triangulate_attributes` over the map's landscape type layers.
+  #[wasm_bindgen(js_name = attributes)]
+  pub fn js_attributes(&self, texture_layer: &[u32], transitions: &[u32], options: &TessellationOptions) -> Result<Box<[f32]>, JsValue> {
+    let transitions = tessellate::transition_triples(transitions).map_err(|err| JsValue::from_str(&format!("Map.attributes: {}", err)))?;
+
+    Ok(self.attributes(texture_layer, &transitions, options).map_err(JsValue::from_str)?.into_boxed_slice())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn section(out: &mut Vec<u8>, tag: &[u8; 8], data: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+  }
+
+  fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect()
+  }
+
+  #[test]
+  fn test_read_map() {
+    let elevation: Vec<u8> = (0..12).map(|i| 10 * i as u8).collect();
+    let mut objects = words(&[1, 1, 2, 9]);
+    objects.extend_from_slice(b"ls_tree01");
+
+    let mut info = words(&[2, 4]);
+    info.extend_from_slice(b"Isle");
+
+    let mut buf = vec![];
+    section(&mut buf, TAG_SIZE, &words(&[4, 3]));
+    section(&mut buf, b"hoixknuu", b"opaque");
+    section(&mut buf, TAG_INFO, &info);
+    section(&mut buf, TAG_ELEVATION, &elevation);
+    section(&mut buf, TAG_TYPES_B, &[3; 12]);
+    section(&mut buf, TAG_OBJECTS, &objects);
+    section(&mut buf, TAG_START, &words(&[2, 0, 1, 3, 2]));
+
+    let map = read_map(&buf).unwrap();
+    assert_eq!((map.width(), map.height(), map.name.as_str(), map.player_count), (4, 3, "Isle", 2));
+    assert_eq!(map.elevation.get(1, 2), Some(90));
+    assert_eq!(map.elevation.get(4, 0), None);
+    assert_eq!((&map.types_a.data[..], &map.types_b.data[..]), (&[0; 12][..], &[3; 12][..]));
+    assert_eq!(map.objects, vec![MapObject { x: 1, y: 2, name: "ls_tree01".to_string() }]);
+    assert_eq!(map.start_positions, vec![(0, 1), (3, 2)]);
+    assert_eq!(map.sections[1].data, b"opaque");
+
+    let options = TessellationOptions::default();
+    let mut tris = vec![0.0; 4 * 3 * 12];
+    tessellate::triangulate_map(&mut tris, 4, 3, &elevation, &options);
+    assert_eq!(map.triangulate(&options), tris);
+    assert!(map.attributes(&[0, 0, 0, 1], &[], &TessellationOptions::default()).is_ok());
+  }
+
+  #[test]
+  fn test_write_map() {
+    let mut buf = vec![];
+    section(&mut buf, b"hoixknuu", b"opaque");
+    section(&mut buf, TAG_SIZE, &words(&[3, 2]));
+    section(&mut buf, TAG_ELEVATION, &[1, 2, 3, 4, 5, 6]);
+    section(&mut buf, TAG_START, &words(&[1, 2, 1]));
+    buf[8] = 7;
+
+    let map = read_map(&buf).unwrap();
+    assert_eq!(write_map(&map).unwrap(), buf);
+
+    let mut edited = map.clone();
+    edited.elevation.data[4] = 50;
+    edited.start_positions.push((2, 0));
+
+    let out = write_map(&edited).unwrap();
+    let sections = read_sections(&out).unwrap();
+    assert_eq!(sections.len(), 4);
+    assert_eq!((&sections[0].tag, sections[0].version, &sections[0].data[..]), (b"hoixknuu", 7, &b"opaque"[..]));
+
+    let mut reread = read_map(&out).unwrap();
+    reread.sections = edited.sections.clone();
+    assert_eq!(reread, edited);
+
+    // Sections the input lacked are appended in a fixed order.
+    edited.name = "Ridge".to_string();
+    edited.objects.push(MapObject { x: 2, y: 1, name: "ls_rock".to_string() });
+    edited.resource_amounts.data[0] = 9;
+
+    let out = write_map(&edited).unwrap();
+    let sections = read_sections(&out).unwrap();
+    let tags: Vec<_> = sections.iter().map(|s| (&s.tag, s.version)).collect();
+    assert_eq!(tags, vec![(b"hoixknuu", 7), (TAG_SIZE, 1), (TAG_ELEVATION, 1), (TAG_START, 1), (TAG_INFO, 1), (TAG_OBJECTS, 1), (TAG_RESOURCES, 1)]);
+
+    let mut reread = read_map(&out).unwrap();
+    reread.sections = edited.sections.clone();
+    assert_eq!(reread, edited);
+
+    let mut fresh = Map::new(5, 4);
+    let tags: Vec<_> = read_sections(&write_map(&fresh).unwrap()).unwrap().into_iter().map(|s| s.tag).collect();
+    assert_eq!(tags, vec![*TAG_SIZE, *TAG_ELEVATION]);
+
+    fresh.types_a.data[3] = 2;
+    fresh.start_positions.push((4, 3));
+    let mut reread = read_map(&write_map(&fresh).unwrap()).unwrap();
+    reread.sections.clear();
+    assert_eq!(reread, fresh);
+
+    edited.types_a.data.pop();
+    assert!(write_map(&edited).is_err());
+  }
+
+  #[test]
+  fn test_read_map_errors() {
+    let mut buf = vec![];
+    section(&mut buf, TAG_SIZE, &words(&[4, 3]));
+    assert!(read_map(&buf).is_err());
+
+    section(&mut buf, TAG_ELEVATION, &[0; 11]);
+    assert!(read_map(&buf).is_err());
+    assert!(read_sections(&buf[..buf.len() - 1]).is_err());
+  }
+}
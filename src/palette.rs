@@ -0,0 +1,239 @@
+use std::fmt::Write;
+
+use crate::quantize::{self, QuantizeOptions};
+
+pub const PALETTE_SIZE: usize = 256;
+pub const RAW_PALETTE_LENGTH: usize = 3 * PALETTE_SIZE;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteFormat {
+  /// 768 bytes of RGB triplets, as stored after the 0x0C marker of a PCX file.
+  Raw,
+  /// Adobe Colour Table: raw triplets, optionally followed by a big-endian
+  /// colour count and transparent index.
+  Act,
+  /// Paint Shop Pro text palette.
+  JascPal,
+  /// GIMP text palette.
+  Gpl,
+}
+
+impl PaletteFormat {
+  pub fn from_name(name: &str) -> Option<PaletteFormat> {
+    match name.to_ascii_lowercase().as_str() {
+      "raw" | "pal768" => Some(PaletteFormat::Raw),
+      "act" => Some(PaletteFormat::Act),
+      "jasc" | "jasc-pal" | "pal" => Some(PaletteFormat::JascPal),
+      "gpl" | "gimp" => Some(PaletteFormat::Gpl),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+  colors: [[u8; 3]; PALETTE_SIZE],
+}
+
+impl Default for Palette {
+  fn default() -> Palette {
+    Palette { colors: [[0u8; 3]; PALETTE_SIZE] }
+  }
+}
+
+impl Palette {
+  pub fn from_raw(buf: &[u8]) -> Result<Palette, &'static str> {
+    if buf.len() < RAW_PALETTE_LENGTH {
+      return Err("Palette: raw palette must be 768 bytes long.");
+    }
+
+    let mut palette = Palette::default();
+    for (c, rgb) in palette.colors.iter_mut().zip(buf.chunks(3)) {
+      c.copy_from_slice(rgb);
+    }
+
+    Ok(palette)
+  }
+
+  pub fn to_raw(&self) -> Vec<u8> {
+    self.colors.iter().flat_map(|c| c.iter().cloned()).collect()
+  }
+
+  pub fn color(&self, index: u8) -> [u8; 3] {
+    self.colors[index as usize]
+  }
+
+  pub fn set_color(&mut self, index: u8, rgb: [u8; 3]) {
+    self.colors[index as usize] = rgb;
+  }
+
+  /// Index of the colour closest to `rgb` in CIE L*a*b*, as `quantize`
+  /// matches. Ties go to the lowest index.
+  pub fn nearest(&self, rgb: [u8; 3]) -> u8 {
+    quantize::Matcher::new(self, &QuantizeOptions::default()).nearest([rgb[0] as f32, rgb[1] as f32, rgb[2] as f32])
+  }
+
+  pub fn import(buf: &[u8], format: PaletteFormat) -> Result<Palette, &'static str> {
+    match format {
+      PaletteFormat::Raw => Palette::from_raw(buf),
+      PaletteFormat::Act => Palette::from_act(buf),
+      PaletteFormat::JascPal => Palette::from_jasc_pal(text(buf)?),
+      PaletteFormat::Gpl => Palette::from_gpl(text(buf)?),
+    }
+  }
+
+  pub fn export(&self, format: PaletteFormat) -> Vec<u8> {
+    match format {
+      PaletteFormat::Raw => self.to_raw(),
+      PaletteFormat::Act => self.to_act(),
+      PaletteFormat::JascPal => self.to_jasc_pal().into_bytes(),
+      PaletteFormat::Gpl => self.to_gpl("Cultures").into_bytes(),
+    }
+  }
+
+  pub fn from_act(buf: &[u8]) -> Result<Palette, &'static str> {
+    let mut palette = Palette::from_raw(buf)?;
+
+    // The optional trailer limits how many entries are in use; the rest is padding.
+    if buf.len() >= RAW_PALETTE_LENGTH + 4 {
+      let count = ((buf[768] as usize) << 8) + buf[769] as usize;
+      for c in palette.colors.iter_mut().skip(count) {
+        *c = [0, 0, 0];
+      }
+    }
+
+    Ok(palette)
+  }
+
+  pub fn to_act(&self) -> Vec<u8> {
+    let mut out = self.to_raw();
+    out.extend_from_slice(&[0x01, 0x00, 0xFF, 0xFF]);
+    out
+  }
+
+  pub fn from_jasc_pal(src: &str) -> Result<Palette, &'static str> {
+    let mut lines = src.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+
+    if lines.next() != Some("JASC-PAL") {
+      return Err("Palette: JASC-PAL header not found.");
+    }
+    lines.next().ok_or("Palette: JASC-PAL version missing.")?;
+    let count: usize = lines.next()
+      .and_then(|l| l.parse().ok())
+      .ok_or("Palette: JASC-PAL colour count missing.")?;
+
+    let mut palette = Palette::default();
+    for i in 0..count.min(PALETTE_SIZE) {
+      let line = lines.next().ok_or("Palette: JASC-PAL has fewer colours than declared.")?;
+      palette.set_color(i as u8, parse_rgb(line)?);
+    }
+
+    Ok(palette)
+  }
+
+  pub fn to_jasc_pal(&self) -> String {
+    let mut out = String::from("JASC-PAL\r\n0100\r\n256\r\n");
+    for c in self.colors.iter() {
+      write!(out, "{} {} {}\r\n", c[0], c[1], c[2]).unwrap();
+    }
+    out
+  }
+
+  pub fn from_gpl(src: &str) -> Result<Palette, &'static str> {
+    let mut lines = src.lines().map(|l| l.trim());
+
+    if lines.next() != Some("GIMP Palette") {
+      return Err("Palette: GIMP Palette header not found.");
+    }
+
+    let mut palette = Palette::default();
+    let mut i = 0;
+
+    for line in lines {
+      if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+        continue;
+      }
+      if i == PALETTE_SIZE {
+        break;
+      }
+
+      palette.set_color(i as u8, parse_rgb(line)?);
+      i += 1;
+    }
+
+    Ok(palette)
+  }
+
+  pub fn to_gpl(&self, name: &str) -> String {
+    let mut out = format!("GIMP Palette\nName: {}\nColumns: 16\n#\n", name);
+    for (i, c) in self.colors.iter().enumerate() {
+      writeln!(out, "{:3} {:3} {:3}\tIndex {}", c[0], c[1], c[2], i).unwrap();
+    }
+    out
+  }
+}
+
+fn text(buf: &[u8]) -> Result<&str, &'static str> {
+  std::str::from_utf8(buf).map_err(|_| "Palette: text palette is not valid UTF-8.")
+}
+
+/// Parses the first three whitespace separated numbers of a line. Anything
+/// after them (GIMP colour names) is ignored.
+fn parse_rgb(line: &str) -> Result<[u8; 3], &'static str> {
+  let mut rgb = [0u8; 3];
+  let mut parts = line.split_whitespace();
+
+  for c in rgb.iter_mut() {
+    *c = parts.next()
+      .and_then(|p| p.parse().ok())
+      .ok_or("Palette: malformed colour entry.")?;
+  }
+
+  Ok(rgb)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn gradient() -> Palette {
+    let mut palette = Palette::default();
+    for i in 0..=255u8 {
+      palette.set_color(i, [i, 255 - i, i / 2]);
+    }
+    palette
+  }
+
+  #[test]
+  fn test_palette_round_trip() {
+    let palette = gradient();
+
+    for format in [PaletteFormat::Raw, PaletteFormat::Act, PaletteFormat::JascPal, PaletteFormat::Gpl].iter() {
+      let buf = palette.export(*format);
+      assert_eq!(Palette::import(&buf, *format).unwrap(), palette, "{:?}", format);
+    }
+  }
+
+  #[test]
+  fn test_palette_nearest() {
+    let mut palette = gradient();
+
+    assert_eq!(palette.nearest([10, 245, 5]), 10);
+    assert_eq!(palette.nearest([251, 3, 126]), 252);
+
+    palette.set_color(200, [10, 245, 5]);
+    assert_eq!(palette.color(200), [10, 245, 5]);
+    assert_eq!(palette.nearest([10, 245, 5]), 10);
+  }
+
+  #[test]
+  fn test_palette_act_count() {
+    let mut buf = gradient().to_act();
+    buf[768] = 0;
+    buf[769] = 2;
+
+    let palette = Palette::from_act(&buf).unwrap();
+    assert_eq!(palette.color(1), [1, 254, 0]);
+    assert_eq!(palette.color(2), [0, 0, 0]);
+  }
+}
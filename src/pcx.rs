@@ -1,65 +1,215 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PcxError {
+  /// The buffer ends before the header, the pixel data or the palette.
+  Truncated,
+  /// The byte after the pixel data is not the 0x0C extended palette marker.
+  MissingPalette,
+  /// An offset from an index table points past the end of the buffer.
+  OffsetOutOfBounds(usize),
+  /// The output buffer is too small for the decoded image.
+  OutputTooSmall,
+  Invalid(&'static str),
+  /// Wraps the error of one image of a texture array.
+  Image(usize, Box<PcxError>),
+}
+
+impl fmt::Display for PcxError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PcxError::Truncated => write!(f, "PCX data is truncated."),
+      PcxError::MissingPalette => write!(f, "PCX extended palette marker 0x0C not found."),
+      PcxError::OffsetOutOfBounds(off) => write!(f, "Offset {} is past the end of the buffer.", off),
+      PcxError::OutputTooSmall => write!(f, "Output buffer is too small for the image."),
+      PcxError::Invalid(msg) => write!(f, "{}", msg),
+      PcxError::Image(i, err) => write!(f, "image #{}: {}", i, err),
+    }
+  }
+}
+
 #[inline]
 fn read_uint16_le(buf: &[u8]) -> u16 {
   ((buf[1] as u16) << 8) + buf[0] as u16
 }
 
-fn read_pixels<'a, 'b>(buf: &'a [u8], pixels: &'b mut Vec<u8>) -> (&'a[u8], &'b[u8]) {
+fn read_pixels<'a, 'b>(buf: &'a [u8], pixels: &'b mut Vec<u8>) -> Result<(&'a[u8], &'b[u8]), PcxError> {
   let mut i = 0;
   let mut pos = 0;
 
   while i < pixels.len() {
-    let mut val = buf[pos]; pos += 1;
+    let mut val = *buf.get(pos).ok_or(PcxError::Truncated)?; pos += 1;
     let mut len = 1;
 
     if val > 192 {
       len = val - 192;
-      val = buf[pos]; pos += 1;
+      val = *buf.get(pos).ok_or(PcxError::Truncated)?; pos += 1;
     }
 
-    while len > 0 {
+    // A run may not spill past the end of the image.
+    while len > 0 && i < pixels.len() {
       pixels[i] = val;
       i += 1;
       len -= 1;
     }
   }
 
-  (&buf[pos..], &pixels[..])
+  Ok((&buf[pos..], &pixels[..]))
 }
 
-pub fn read_palette(buf: &[u8]) -> Result<&[u8], &'static str> {
-  if buf[0] != 0x0C {
-    return Err("PCX extended palette marker 0x0C not found.");
+/// Decodes the pixel data of a PCX file, dropping the padding at the end of
+/// each scanline when BytesPerLine is larger than the width.
+fn read_image(buf: &[u8], width: usize, height: usize) -> Result<(&[u8], Vec<u8>), PcxError> {
+  if buf.len() < 0x80 {
+    return Err(PcxError::Truncated);
+  }
+
+  let bytes_per_line = (read_uint16_le(&buf[66..68]) as usize).max(width);
+  let mut pixels = vec![0; bytes_per_line * height];
+  let (rest, _) = read_pixels(&buf[0x80..], &mut pixels)?;
+
+  if bytes_per_line > width {
+    pixels = pixels.chunks(bytes_per_line).flat_map(|row| row[..width].iter().cloned()).collect();
+  }
+
+  Ok((rest, pixels))
+}
+
+pub fn read_palette(buf: &[u8]) -> Result<&[u8], PcxError> {
+  if buf.first() != Some(&0x0C) {
+    return Err(PcxError::MissingPalette);
+  }
+  if buf.len() < 769 {
+    return Err(PcxError::Truncated);
   }
 
   Ok(&buf[1..769])
 }
 
-fn get_dimensions(buf: &[u8]) -> (usize, usize) {
+pub fn get_dimensions(buf: &[u8]) -> Result<(usize, usize), PcxError> {
+  if buf.len() < 0x80 {
+    return Err(PcxError::Truncated);
+  }
+
   let x0 = read_uint16_le(&buf[4..6]) as usize;
   let y0 = read_uint16_le(&buf[6..8]) as usize;
   let x1 = read_uint16_le(&buf[8..10]) as usize;
   let y1 = read_uint16_le(&buf[10..12]) as usize;
 
-  return (x1 - x0 + 1, y1 - y0 + 1);
+  if x1 < x0 || y1 < y0 {
+    return Err(PcxError::Invalid("PCX image window is empty."));
+  }
+
+  return Ok((x1 - x0 + 1, y1 - y0 + 1));
+}
+
+/// Which property of a mask pixel becomes the alpha of the masked texture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaskChannel {
+  /// The raw palette index of the mask pixel. Only meaningful for masks whose
+  /// palette is a linear 0..255 ramp.
+  Index,
+  /// Rec. 601 luminance of the mask pixel's palette colour.
+  Luminance,
+  Red,
+  Green,
+  Blue,
+}
+
+impl MaskChannel {
+  pub fn from_u8(val: u8) -> Option<MaskChannel> {
+    match val {
+      0 => Some(MaskChannel::Index),
+      1 => Some(MaskChannel::Luminance),
+      2 => Some(MaskChannel::Red),
+      3 => Some(MaskChannel::Green),
+      4 => Some(MaskChannel::Blue),
+      _ => None,
+    }
+  }
+}
+
+/// Mapping from mask PCX pixels to texture alpha.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MaskAlpha {
+  pub channel: MaskChannel,
+  /// When set, alpha becomes 0xFF for values at or above the threshold and 0
+  /// below it.
+  pub threshold: Option<u8>,
+}
+
+/// Masks are decoded through their own palette by default, so the alpha ramp
+/// doesn't depend on how the mask's palette indices are ordered.
+impl Default for MaskAlpha {
+  fn default() -> MaskAlpha {
+    MaskAlpha { channel: MaskChannel::Luminance, threshold: None }
+  }
 }
 
-pub fn pcx_read<'a>(buf: &'a[u8], out: &mut [u8], mask: Option<&[u8]>) -> &'a[u8] {
-  let (width, height) = get_dimensions(&buf);
+impl MaskAlpha {
+  pub fn alpha(&self, index: u8, palette: &[u8]) -> u8 {
+    let i = 3 * index as usize;
+
+    let val = match self.channel {
+      MaskChannel::Index => index,
+      MaskChannel::Luminance => {
+        ((299 * palette[i] as u32 + 587 * palette[i + 1] as u32 + 114 * palette[i + 2] as u32) / 1000) as u8
+      },
+      MaskChannel::Red => palette[i],
+      MaskChannel::Green => palette[i + 1],
+      MaskChannel::Blue => palette[i + 2],
+    };
+
+    match self.threshold {
+      None => val,
+      Some(t) => if val >= t { 0xFF } else { 0x00 },
+    }
+  }
+}
+
+fn read_mask(mask_buf: &[u8], len: usize, mask_alpha: &MaskAlpha) -> Result<Vec<u8>, PcxError> {
+  let (width, height) = get_dimensions(mask_buf)?;
+  if width * height != len {
+    return Err(PcxError::Invalid("Mask size differs from the image size."));
+  }
+
+  let (rest, mut mask_out_buf) = read_image(mask_buf, width, height)?;
+
+  // Raw indices don't need the mask's palette, so masks without one keep working.
+  let palette = match mask_alpha.channel {
+    MaskChannel::Index => &[][..],
+    _ => read_palette(&rest)?,
+  };
+
+  for a in mask_out_buf.iter_mut() {
+    *a = mask_alpha.alpha(*a, palette);
+  }
+
+  Ok(mask_out_buf)
+}
+
+/// Decodes a mask PCX into one alpha byte per pixel.
+pub fn pcx_read_alpha(buf: &[u8], mask_alpha: &MaskAlpha) -> Result<Vec<u8>, PcxError> {
+  let (width, height) = get_dimensions(buf)?;
+
+  read_mask(buf, width * height, mask_alpha)
+}
+
+pub fn pcx_read<'a>(buf: &'a[u8], out: &mut [u8], mask: Option<&[u8]>, mask_alpha: &MaskAlpha) -> Result<&'a[u8], PcxError> {
+  let (width, height) = get_dimensions(&buf)?;
   let buf_length = width * height;
 
+  if out.len() < 4 * buf_length {
+    return Err(PcxError::OutputTooSmall);
+  }
+
   let alpha = match mask {
     None => vec![0xFFu8; buf_length],
-    Some(mask_buf) => {
-      let mut mask_out_buf = vec![0xFFu8; buf_length];
-      read_pixels(&mask_buf[0x80..], &mut mask_out_buf);
-
-      mask_out_buf
-    }
+    Some(mask_buf) => read_mask(mask_buf, buf_length, mask_alpha)?,
   };
 
-  let mut pixels = vec![0; buf_length];
-  let (rest, _) = read_pixels(&buf[0x80..], &mut pixels);
-  let palette = read_palette(&rest).expect("read_palette failed.");
+  let (rest, pixels) = read_image(buf, width, height)?;
+  let palette = read_palette(&rest)?;
 
   for i in 0..pixels.len() {
     out[4 * i + 0] = palette[0 + 3 * pixels[i] as usize];
@@ -68,31 +218,165 @@ pub fn pcx_read<'a>(buf: &'a[u8], out: &mut [u8], mask: Option<&[u8]>) -> &'a[u8
     out[4 * i + 3] = alpha[i];
   }
 
-  return rest;
+  return Ok(rest);
+}
+
+fn slice_at(buf: &[u8], offset: usize) -> Result<&[u8], PcxError> {
+  buf.get(offset..).ok_or(PcxError::OffsetOutOfBounds(offset))
+}
+
+/// Decodes the PCX images at `index_table` offsets into consecutive RGBA
+/// layers. Errors are wrapped in `PcxError::Image` with the failing position.
+pub fn pcx_texture_array(buf: &[u8], out: &mut [u8], index_table: &[usize], mask_index_table: Option<&[usize]>, mask_alpha: &MaskAlpha) -> Result<(), PcxError> {
+  if mask_index_table.is_some_and(|mit| mit.len() < index_table.len()) {
+    return Err(PcxError::Invalid("Mask index table is shorter than the index table."));
+  }
+
+  let slices = |table: &[usize]| -> Result<Vec<&[u8]>, PcxError> {
+    table.iter().enumerate()
+      .map(|(i, idx)| slice_at(buf, *idx).map_err(|err| PcxError::Image(i, Box::new(err))))
+      .collect()
+  };
+
+  let images = slices(index_table)?;
+  let masks = match mask_index_table {
+    None => None,
+    Some(mit) => Some(slices(&mit[..index_table.len()])?),
+  };
+
+  pcx_images_texture_array(&images, masks.as_deref(), out, mask_alpha)
 }
 
-pub fn pcx_texture_array(buf: &[u8], out: &mut [u8], index_table: &[usize], mask_index_table: Option<&[usize]>) {
-  let (width, height) = get_dimensions(&buf);
+/// Same as `pcx_texture_array` for images that are already separate slices,
+/// e.g. files of a `.lib` archive. Every image must have the size of the
+/// first one, which sets the layer stride.
+pub fn pcx_images_texture_array(images: &[&[u8]], masks: Option<&[&[u8]]>, out: &mut [u8], mask_alpha: &MaskAlpha) -> Result<(), PcxError> {
+  if images.is_empty() {
+    return Ok(());
+  }
+  if masks.is_some_and(|m| m.len() < images.len()) {
+    return Err(PcxError::Invalid("Mask table is shorter than the image table."));
+  }
+
+  let (width, height) = get_dimensions(images[0]).map_err(|err| PcxError::Image(0, Box::new(err)))?;
   let len = width * height * 4;
-  for (i, idx) in index_table.iter().enumerate() {
-    pcx_read(&buf[*idx..], &mut out[(i * len)..], mask_index_table.and_then(|mit| Some(&buf[mit[i]..])));
+
+  for (i, image) in images.iter().enumerate() {
+    let mask = masks.map(|m| m[i]);
+
+    let result = match (get_dimensions(image), out.get_mut(i * len..)) {
+      (Err(err), _) => Err(err),
+      (Ok(size), _) if size != (width, height) => Err(PcxError::Invalid("Image size differs from the first image.")),
+      (_, Some(layer)) => pcx_read(image, layer, mask, mask_alpha).map(|_| ()),
+      (_, None) => Err(PcxError::OutputTooSmall),
+    };
+
+    result.map_err(|err| PcxError::Image(i, Box::new(err)))?;
   }
+
+  Ok(())
 }
 
-pub fn pcx_read_palette_array<'a>(buf: &'a[u8], index: &[usize]) -> Vec<&'a[u8]> {
-  let mut out: Vec<&'a[u8]> = vec![buf; index.len()];
+/// Palettes of the PCX files that end at the next offset in `index`, the last
+/// one at the end of `buf`.
+pub fn pcx_read_palette_array<'a>(buf: &'a[u8], index: &[usize]) -> Result<Vec<&'a[u8]>, PcxError> {
+  let mut files: Vec<&'a[u8]> = Vec::with_capacity(index.len());
 
   for (i, pos) in index.iter().enumerate() {
-    let length = if i < index.len() - 1 {
-      index[i + 1] - index[i]
-    } else {
-      buf.len() - index[i]
-    };
+    let end = if i + 1 < index.len() { index[i + 1] } else { buf.len() };
+
+    if end > buf.len() {
+      return Err(PcxError::Image(i, Box::new(PcxError::OffsetOutOfBounds(end))));
+    }
+    if end < *pos + 769 {
+      return Err(PcxError::Image(i, Box::new(PcxError::Truncated)));
+    }
+
+    files.push(&buf[*pos..end]);
+  }
+
+  pcx_palettes(&files)
+}
+
+/// The extended palette at the end of every PCX file in `files`.
+pub fn pcx_palettes<'a>(files: &[&'a[u8]]) -> Result<Vec<&'a[u8]>, PcxError> {
+  files.iter().enumerate().map(|(i, file)| {
+    if file.len() < 769 {
+      return Err(PcxError::Image(i, Box::new(PcxError::Truncated)));
+    }
+
+    read_palette(&file[file.len() - 769..]).map_err(|err| PcxError::Image(i, Box::new(err)))
+  }).collect()
+}
+
+#[inline]
+fn write_uint16_le(buf: &mut [u8], val: u16) {
+  buf[0] = (val & 0xFF) as u8;
+  buf[1] = ((val & 0xFF00) >> 8) as u8;
+}
+
+fn write_pixels(row: &[u8], out: &mut Vec<u8>) {
+  let mut i = 0;
+
+  while i < row.len() {
+    let val = row[i];
+    let mut len = 1;
+
+    while i + len < row.len() && row[i + len] == val && len < 63 {
+      len += 1;
+    }
+
+    // read_pixels treats anything above 192 as a run, so those values and 192
+    // itself are always written as runs.
+    if len > 1 || val >= 192 {
+      out.push(192 + len as u8);
+    }
+    out.push(val);
+
+    i += len;
+  }
+}
+
+/// Encodes 8-bit indexed pixels as a run-length encoded PCX with an extended
+/// palette. Scanlines are padded with index 0 to the even BytesPerLine the
+/// format requires.
+pub fn pcx_write(width: usize, height: usize, pixels: &[u8], palette: &[u8]) -> Result<Vec<u8>, PcxError> {
+  // The window end is stored as width - 1, but BytesPerLine has to hold the
+  // padded width too.
+  if width == 0 || height == 0 || width > 0xFFFE || height > 0x10000 {
+    return Err(PcxError::Invalid("pcx_write: image dimensions out of range."));
+  }
+  if pixels.len() < width * height {
+    return Err(PcxError::Invalid("pcx_write: pixel buffer is shorter than width * height."));
+  }
+  if palette.len() < 768 {
+    return Err(PcxError::Invalid("pcx_write: palette must be 768 bytes long."));
+  }
 
-    out[i] = read_palette(&buf[*pos + length - 769..]).expect("read_palette failed");
+  let mut out = vec![0u8; 0x80];
+  out[0] = 0x0A;  // Manufacturer
+  out[1] = 5;     // Version 3.0+
+  out[2] = 1;     // RLE encoding
+  out[3] = 8;     // Bits per pixel
+  write_uint16_le(&mut out[8..10], (width - 1) as u16);
+  write_uint16_le(&mut out[10..12], (height - 1) as u16);
+  write_uint16_le(&mut out[12..14], 72);
+  write_uint16_le(&mut out[14..16], 72);
+  out[65] = 1;    // Planes
+  let bytes_per_line = width + width % 2;
+  write_uint16_le(&mut out[66..68], bytes_per_line as u16);
+  write_uint16_le(&mut out[68..70], 1);
+
+  let mut line = vec![0u8; bytes_per_line];
+  for row in pixels[..width * height].chunks(width) {
+    line[..width].copy_from_slice(row);
+    write_pixels(&line, &mut out);
   }
 
-  return out;
+  out.push(0x0C);
+  out.extend_from_slice(&palette[..768]);
+
+  return Ok(out);
 }
 
 // pub fn pcx_read_palette(buf: &[u8], ) {
@@ -118,7 +402,7 @@ mod tests {
     let mut buffer = Vec::new();
 
     buf_reader.read_to_end(&mut buffer).expect("read_to_end failed.");
-    let (width, height) = get_dimensions(&buffer);
+    let (width, height) = get_dimensions(&buffer).unwrap();
 
     assert_eq!(width, 256);
     assert_eq!(height, 256);
@@ -133,7 +417,90 @@ mod tests {
     buf_reader.read_to_end(&mut buffer).expect("read_to_end failed.");
 
     let mut out = [0u8; 256 * 256 * 4];
-    pcx_read(&buffer, &mut out, None);
+    pcx_read(&buffer, &mut out, None, &MaskAlpha::default()).expect("pcx_read failed.");
+  }
+
+  #[test]
+  fn test_pcx_write_round_trip() {
+    let palette: Vec<u8> = (0..768).map(|i| (i % 256) as u8).collect();
+    let pixels: Vec<u8> = (0..64 * 3).map(|i| if i < 100 { 7 } else { (i * 13 % 256) as u8 }).collect();
+
+    let buf = pcx_write(64, 3, &pixels, &palette).unwrap();
+    assert_eq!(get_dimensions(&buf), Ok((64, 3)));
+
+    let mut out = vec![0u8; 64 * 3 * 4];
+    pcx_read(&buf, &mut out, None, &MaskAlpha::default()).unwrap();
+
+    for (i, p) in pixels.iter().enumerate() {
+      assert_eq!(&out[4 * i..4 * i + 3], &palette[3 * *p as usize..3 * *p as usize + 3]);
+    }
+  }
+
+  #[test]
+  fn test_pcx_write_odd_width() {
+    let palette: Vec<u8> = (0..768).map(|i| (i % 256) as u8).collect();
+    let pixels = [1u8, 2, 3, 200, 200, 200];
+
+    let buf = pcx_write(3, 2, &pixels, &palette).unwrap();
+    assert_eq!(read_uint16_le(&buf[66..68]), 4);
+
+    let mut out = vec![0u8; 3 * 2 * 4];
+    pcx_read(&buf, &mut out, None, &MaskAlpha::default()).unwrap();
+    for (i, p) in pixels.iter().enumerate() {
+      assert_eq!(out[4 * i], palette[3 * *p as usize]);
+    }
+
+    assert!(pcx_write(0xFFFF, 1, &vec![0; 0xFFFF], &palette).is_err());
+    assert!(pcx_write(0, 1, &[], &palette).is_err());
+  }
+
+  fn mask_palette() -> Vec<u8> {
+    let mut palette = vec![0u8; 768];
+    palette[3..6].copy_from_slice(&[200, 100, 50]);
+    palette[6..9].copy_from_slice(&[0, 0, 255]);
+    palette
+  }
+
+  #[test]
+  fn test_mask_alpha_channels() {
+    let palette = mask_palette();
+
+    assert_eq!(MaskAlpha::default().alpha(1, &palette), 124);
+    assert_eq!(MaskAlpha { channel: MaskChannel::Index, threshold: None }.alpha(1, &palette), 1);
+    assert_eq!(MaskAlpha { channel: MaskChannel::Luminance, threshold: None }.alpha(1, &palette), 124);
+    assert_eq!(MaskAlpha { channel: MaskChannel::Red, threshold: None }.alpha(1, &palette), 200);
+    assert_eq!(MaskAlpha { channel: MaskChannel::Blue, threshold: None }.alpha(2, &palette), 255);
+  }
+
+  #[test]
+  fn test_mask_alpha_threshold() {
+    let palette = mask_palette();
+    let mask_alpha = MaskAlpha { channel: MaskChannel::Green, threshold: Some(100) };
+
+    assert_eq!(mask_alpha.alpha(1, &palette), 0xFF);
+    assert_eq!(mask_alpha.alpha(2, &palette), 0x00);
+  }
+
+  #[test]
+  fn test_pcx_read_mask() {
+    let palette = vec![90u8; 768];
+    let image = pcx_write(2, 2, &[0, 1, 2, 3], &palette).unwrap();
+    // Index 1 is white and index 2 black, the reverse of a linear index ramp.
+    let mut mask_palette = mask_palette();
+    mask_palette[3..6].copy_from_slice(&[255, 255, 255]);
+    mask_palette[6..9].copy_from_slice(&[0, 0, 0]);
+    let mask = pcx_write(2, 2, &[1, 2, 1, 2], &mask_palette).unwrap();
+
+    let mut out = vec![0u8; 2 * 2 * 4];
+    pcx_read(&image, &mut out, Some(&mask), &MaskAlpha::default()).unwrap();
+    assert_eq!([out[3], out[7], out[11], out[15]], [255, 0, 255, 0]);
+
+    let mut buf = image.clone();
+    buf.extend_from_slice(&mask);
+    let mut layers = vec![0u8; 2 * 2 * 4];
+    let raw = MaskAlpha { channel: MaskChannel::Index, threshold: None };
+    pcx_texture_array(&buf, &mut layers, &[0], Some(&[image.len()]), &raw).unwrap();
+    assert_eq!([layers[3], layers[7], layers[11], layers[15]], [1, 2, 1, 2]);
   }
 
   #[test]
@@ -144,6 +511,29 @@ mod tests {
 
     buf_reader.read_to_end(&mut buffer).expect("read_to_end failed.");
 
-    pcx_read_palette_array(&buffer[..], &[0usize; 1]);
+    pcx_read_palette_array(&buffer[..], &[0usize; 1]).expect("pcx_read_palette_array failed.");
+  }
+
+  #[test]
+  fn test_pcx_errors() {
+    let palette = vec![0u8; 768];
+    let buf = pcx_write(4, 4, &[1u8; 16], &palette).unwrap();
+    let mut out = vec![0u8; 4 * 4 * 4];
+
+    assert_eq!(pcx_read(&buf[..0x40], &mut out, None, &MaskAlpha::default()), Err(PcxError::Truncated));
+    assert_eq!(pcx_read(&buf[..buf.len() - 769], &mut out, None, &MaskAlpha::default()), Err(PcxError::MissingPalette));
+    assert_eq!(pcx_read(&buf, &mut out[..10], None, &MaskAlpha::default()), Err(PcxError::OutputTooSmall));
+
+    assert_eq!(
+      pcx_texture_array(&buf, &mut out, &[0, buf.len() + 1], None, &MaskAlpha::default()),
+      Err(PcxError::Image(1, Box::new(PcxError::OffsetOutOfBounds(buf.len() + 1))))
+    );
+    let larger = pcx_write(8, 4, &[1u8; 32], &palette).unwrap();
+    let mut out = vec![0u8; 2 * 8 * 4 * 4];
+    assert_eq!(
+      pcx_images_texture_array(&[&buf, &larger], None, &mut out, &MaskAlpha::default()),
+      Err(PcxError::Image(1, Box::new(PcxError::Invalid("Image size differs from the first image."))))
+    );
+    assert_eq!(pcx_read_palette_array(&buf, &[]), Ok(vec![]));
   }
 }
@@ -0,0 +1,202 @@
+use crate::palette::{Palette, PALETTE_SIZE};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dither {
+  None,
+  FloydSteinberg,
+  /// 4x4 Bayer matrix.
+  Ordered,
+}
+
+impl Dither {
+  pub fn from_u8(val: u8) -> Option<Dither> {
+    match val {
+      0 => Some(Dither::None),
+      1 => Some(Dither::FloydSteinberg),
+      2 => Some(Dither::Ordered),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct QuantizeOptions {
+  pub dither: Dither,
+  /// Inclusive index ranges the quantiser never emits, e.g. the player colours
+  /// that the game swaps at runtime.
+  pub reserved: Vec<(u8, u8)>,
+  /// Pixels with alpha below 128 get this index instead of a colour match.
+  pub transparent_index: Option<u8>,
+}
+
+impl Default for QuantizeOptions {
+  fn default() -> QuantizeOptions {
+    QuantizeOptions { dither: Dither::None, reserved: vec![], transparent_index: None }
+  }
+}
+
+const BAYER_4X4: [[f32; 4]; 4] = [
+  [0.0, 8.0, 2.0, 10.0],
+  [12.0, 4.0, 14.0, 6.0],
+  [3.0, 11.0, 1.0, 9.0],
+  [15.0, 7.0, 13.0, 5.0],
+];
+
+/// Amplitude of the ordered dither offset in 8-bit RGB steps.
+const ORDERED_SPREAD: f32 = 32.0;
+
+fn srgb_to_linear(c: f32) -> f32 {
+  let c = c / 255.0;
+  if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn lab_f(t: f32) -> f32 {
+  if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 }
+}
+
+/// CIE L*a*b* (D65) of an sRGB colour, used so that matching follows perceived
+/// rather than numeric difference.
+fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+  let r = srgb_to_linear(rgb[0].clamp(0.0, 255.0));
+  let g = srgb_to_linear(rgb[1].clamp(0.0, 255.0));
+  let b = srgb_to_linear(rgb[2].clamp(0.0, 255.0));
+
+  let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+  let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+  let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+  let (fx, fy, fz) = (lab_f(x), lab_f(y), lab_f(z));
+
+  [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+pub(crate) struct Matcher {
+  candidates: Vec<(u8, [f32; 3])>,
+}
+
+impl Matcher {
+  pub(crate) fn new(palette: &Palette, options: &QuantizeOptions) -> Matcher {
+    let candidates = (0..PALETTE_SIZE)
+      .map(|i| i as u8)
+      .filter(|&i| !options.reserved.iter().any(|&(lo, hi)| lo <= i && i <= hi))
+      .filter(|&i| Some(i) != options.transparent_index)
+      .map(|i| {
+        let c = palette.color(i);
+        (i, rgb_to_lab([c[0] as f32, c[1] as f32, c[2] as f32]))
+      })
+      .collect();
+
+    Matcher { candidates }
+  }
+
+  pub(crate) fn nearest(&self, rgb: [f32; 3]) -> u8 {
+    let lab = rgb_to_lab(rgb);
+
+    self.candidates.iter()
+      .map(|(i, c)| {
+        let d = (c[0] - lab[0]).powi(2) + (c[1] - lab[1]).powi(2) + (c[2] - lab[2]).powi(2);
+        (*i, d)
+      })
+      .fold((0u8, f32::MAX), |best, cur| if cur.1 < best.1 { cur } else { best })
+      .0
+  }
+}
+
+/// Maps an RGBA image onto the indices of `palette`.
+pub fn quantize(rgba: &[u8], width: usize, height: usize, palette: &Palette, options: &QuantizeOptions) -> Result<Vec<u8>, &'static str> {
+  if rgba.len() < width * height * 4 {
+    return Err("quantize: RGBA buffer is shorter than width * height * 4.");
+  }
+
+  let matcher = Matcher::new(palette, options);
+  if matcher.candidates.is_empty() {
+    return Err("quantize: every palette index is reserved.");
+  }
+
+  let mut out = vec![0u8; width * height];
+  // Floyd-Steinberg error carried into the current and the next row.
+  let mut err = vec![[0f32; 3]; 2 * (width + 2)];
+
+  for y in 0..height {
+    let (cur, next) = err.split_at_mut(width + 2);
+
+    for x in 0..width {
+      let p = 4 * (y * width + x);
+      let i = y * width + x;
+
+      if rgba[p + 3] < 128 {
+        if let Some(t) = options.transparent_index {
+          out[i] = t;
+          continue;
+        }
+      }
+
+      let mut rgb = [rgba[p] as f32, rgba[p + 1] as f32, rgba[p + 2] as f32];
+
+      match options.dither {
+        Dither::None => {},
+        Dither::FloydSteinberg => {
+          for c in 0..3 { rgb[c] += cur[x + 1][c]; }
+        },
+        Dither::Ordered => {
+          let offset = (BAYER_4X4[y % 4][x % 4] / 16.0 - 0.5) * ORDERED_SPREAD;
+          for c in rgb.iter_mut() { *c += offset; }
+        },
+      }
+
+      let index = matcher.nearest(rgb);
+      out[i] = index;
+
+      if options.dither == Dither::FloydSteinberg {
+        let m = palette.color(index);
+
+        for c in 0..3 {
+          let e = rgb[c] - m[c] as f32;
+          cur[x + 2][c] += e * 7.0 / 16.0;
+          next[x][c] += e * 3.0 / 16.0;
+          next[x + 1][c] += e * 5.0 / 16.0;
+          next[x + 2][c] += e * 1.0 / 16.0;
+        }
+      }
+    }
+
+    cur.copy_from_slice(next);
+    for e in next.iter_mut() { *e = [0.0; 3]; }
+  }
+
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn grey_palette() -> Palette {
+    let raw: Vec<u8> = (0..=255u8).flat_map(|i| vec![i, i, i]).collect();
+    Palette::from_raw(&raw).unwrap()
+  }
+
+  #[test]
+  fn test_quantize_exact_colours() {
+    let palette = grey_palette();
+    let rgba = [0, 0, 0, 255, 100, 100, 100, 255, 255, 255, 255, 255, 7, 7, 7, 0];
+
+    let out = quantize(&rgba, 4, 1, &palette, &QuantizeOptions::default()).unwrap();
+    assert_eq!(out, vec![0, 100, 255, 7]);
+  }
+
+  #[test]
+  fn test_quantize_reserved_and_transparent() {
+    let palette = grey_palette();
+    let rgba = [100, 100, 100, 255, 50, 50, 50, 0];
+    let options = QuantizeOptions {
+      dither: Dither::FloydSteinberg,
+      reserved: vec![(96, 110)],
+      transparent_index: Some(0),
+    };
+
+    let out = quantize(&rgba, 2, 1, &palette, &options).unwrap();
+    assert!(out[0] == 95 || out[0] == 111);
+    assert_eq!(out[1], 0);
+  }
+}
@@ -0,0 +1,71 @@
+use crate::codepage;
+
+/// Little endian reads from the start of a buffer, as used by the game's
+/// file formats. Every read fails with the `truncated` message of the format
+/// once the buffer runs out.
+pub struct Reader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+  truncated: &'static str,
+}
+
+impl<'a> Reader<'a> {
+  pub fn new(buf: &'a [u8], truncated: &'static str) -> Reader<'a> {
+    Reader { buf, pos: 0, truncated }
+  }
+
+  #[cfg_attr(not(feature = "unverified-formats"), allow(dead_code))]
+  pub fn is_empty(&self) -> bool {
+    self.pos >= self.buf.len()
+  }
+
+  /// Everything not read yet.
+  pub fn rest(&self) -> &'a [u8] {
+    self.buf.get(self.pos..).unwrap_or(&[])
+  }
+
+  pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+    let b = self.buf.get(self.pos..self.pos.saturating_add(len)).ok_or(self.truncated)?;
+    self.pos += len;
+
+    Ok(b)
+  }
+
+  pub fn u32(&mut self) -> Result<u32, &'static str> {
+    let b = self.bytes(4)?;
+
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  #[cfg_attr(not(feature = "unverified-formats"), allow(dead_code))]
+  pub fn i32(&mut self) -> Result<i32, &'static str> {
+    Ok(self.u32()? as i32)
+  }
+
+  /// A u32 byte length followed by text in the game's code page, see
+  /// `codepage`.
+  #[cfg_attr(not(feature = "unverified-formats"), allow(dead_code))]
+  pub fn string(&mut self) -> Result<String, &'static str> {
+    let len = self.u32()? as usize;
+
+    Ok(codepage::decode(self.bytes(len)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reader() {
+    let buf = [2, 0, 0, 0, b'h', 0x80, 0xFF, 0xFF, 0xFF, 0xFF, 7];
+    let mut r = Reader::new(&buf, "Truncated.");
+
+    assert_eq!(r.string(), Ok("h\u{20AC}".to_string()));
+    assert_eq!(r.i32(), Ok(-1));
+    assert!(!r.is_empty());
+    assert_eq!(r.rest(), &[7]);
+    assert_eq!(r.u32(), Err("Truncated."));
+    assert_eq!(r.bytes(usize::MAX), Err("Truncated."));
+  }
+}
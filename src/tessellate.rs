@@ -1,43 +1,891 @@
 // use rayon::prelude::*;
+use wasm_bindgen::prelude::*;
 
-fn elevation_at(i: usize, w: usize, h: usize, elv: &[u8]) -> [f32; 4] {
+use crate::transition;
+
+/// Where cells on the edge of the map take their heights from.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BorderMode {
+  /// Border cells are flat at height 0.
+  Zero = 0,
+  /// Border cells use their samples; corners outside the map repeat the
+  /// nearest edge sample.
+  Clamp = 1,
+  /// Corners outside the map wrap around to the opposite edge.
+  Wrap = 2,
+  /// Border cells use their samples; cells with a corner outside the map are
+  /// left out of the mesh.
+  Skip = 3,
+}
+
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TessellationOptions {
+  /// Height units per elevation step.
+  pub height_scale: f32,
+  /// Scale of the grid; a cell is `2 * cell_size` wide and tall.
+  pub cell_size: f32,
+  pub border_mode: BorderMode,
+  /// When unset, border cells are left out of the mesh.
+  ///
+  /// Left out cells are dropped from `triangulate_map_indices`. The flat
+  /// `triangulate_map` buffer keeps one slot per cell so that it lines up with
+  /// `update_region` and the attribute buffers; there they collapse into a
+  /// point.
+  pub emit_border: bool,
+  /// Landscape textures repeat every this many cells in both directions.
+  pub texture_repeat_cells: f32,
+}
+
+impl Default for TessellationOptions {
+  fn default() -> TessellationOptions {
+    TessellationOptions {
+      height_scale: 1.0 / 16.0,
+      cell_size: 1.0,
+      border_mode: BorderMode::Zero,
+      emit_border: true,
+      texture_repeat_cells: 8.0,
+    }
+  }
+}
+
+#[wasm_bindgen]
+impl TessellationOptions {
+  #[wasm_bindgen(constructor)]
+  pub fn new() -> TessellationOptions {
+    TessellationOptions::default()
+  }
+}
+
+impl TessellationOptions {
+  /// Largest distance elevation can move a vertex up or down.
+  pub fn max_lift(&self) -> f32 {
+    255.0 * self.height_scale.abs()
+  }
+}
+
+#[inline]
+fn is_border(i: usize, w: usize, h: usize) -> bool {
   let row = i / w;
   let col = i % w;
 
-  if row == 0 || row == h - 1 || col == 0 || col == w - 1 {
+  row == 0 || row == h - 1 || col == 0 || col == w - 1
+}
+
+/// Height of sample `(sx, sy)`, resolving samples outside the map through the
+/// border mode. With `BorderMode::Skip` only left out cells reach outside the
+/// map; those samples read as 0.
+pub fn sample_height(sx: isize, sy: isize, w: usize, h: usize, elv: &[u8], options: &TessellationOptions) -> f32 {
+  let (wi, hi) = (w as isize, h as isize);
+
+  let (sx, sy) = if sx >= 0 && sy >= 0 && sx < wi && sy < hi {
+    (sx, sy)
+  } else {
+    match options.border_mode {
+      BorderMode::Zero | BorderMode::Skip => return 0.0,
+      BorderMode::Clamp => (sx.max(0).min(wi - 1), sy.max(0).min(hi - 1)),
+      BorderMode::Wrap => (sx.rem_euclid(wi), sy.rem_euclid(hi)),
+    }
+  };
+
+  elv[sy as usize * w + sx as usize] as f32 * options.height_scale
+}
+
+pub fn elevation_at(i: usize, w: usize, h: usize, elv: &[u8], options: &TessellationOptions) -> [f32; 4] {
+  if options.border_mode == BorderMode::Zero && is_border(i, w, h) {
     return [0.0, 0.0, 0.0, 0.0];
   }
 
+  let x = (i % w) as isize;
+  let y = (i / w) as isize;
+  let odd = y % 2;
+
   return [
-    (elv[i] as f32) / 16.0,
-    (elv[i + w + (i / w % 2)] as f32) / 16.0,
-    (elv[i + w + (i / w % 2) - 1] as f32) / 16.0,
-    (elv[i + 1] as f32) / 16.0,
+    sample_height(x, y, w, h, elv, options),
+    sample_height(x + odd, y + 1, w, h, elv, options),
+    sample_height(x + odd - 1, y + 1, w, h, elv, options),
+    sample_height(x + 1, y, w, h, elv, options),
   ];
 }
 
-pub fn triangulate_map(map: &mut Vec<f32>, width: usize, height: usize, elevation: &[u8]) {
+/// Whether one of the corners of cell `i` lies outside the map.
+fn has_outside_corner(i: usize, w: usize, h: usize) -> bool {
+  let x = i % w;
+  let y = i / w;
+  let odd = y % 2;
+
+  y + 1 >= h || x + 1 >= w || (odd == 0 && x == 0)
+}
+
+/// Whether cell `i` is part of the mesh; see `emit_border` and
+/// `BorderMode::Skip`.
+pub fn cell_emitted(i: usize, w: usize, h: usize, options: &TessellationOptions) -> bool {
+  if !options.emit_border && is_border(i, w, h) {
+    return false;
+  }
+
+  !(options.border_mode == BorderMode::Skip && has_outside_corner(i, w, h))
+}
+
+fn triangulate_cell(r: &mut [f32], i: usize, width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) {
+  let x = i % width;
+  let y = i / width;
+
+  let cs = options.cell_size;
+  let off = (y % 2) as f32;
+  let elv = elevation_at(i, width, height, elevation, options);
+  let fx = 2.0 * x as f32;
+  let fy = 2.0 * y as f32;
+
+  if !cell_emitted(i, width, height, options) {
+    for v in r[..12].chunks_mut(2) {
+      v[0] = cs * (fx + off);
+      v[1] = cs * fy - elv[0];
+    }
+    return;
+  }
+
+  r[0] = cs * (fx + 0.0 + off);
+  r[1] = cs * (fy + 0.0) - elv[0];
+  r[2] = cs * (fx + 1.0 + off);
+  r[3] = cs * (fy + 2.0) - elv[1];
+  r[4] = cs * (fx - 1.0 + off);
+  r[5] = cs * (fy + 2.0) - elv[2];
+
+  r[6] = cs * (fx + 0.0 + off);
+  r[7] = cs * (fy + 0.0) - elv[0];
+  r[8] = cs * (fx + 2.0 + off);
+  r[9] = cs * (fy + 0.0) - elv[3];
+  r[10] = cs * (fx + 1.0 + off);
+  r[11] = cs * (fy + 2.0) - elv[1];
+}
+
+pub fn triangulate_map(map: &mut Vec<f32>, width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) {
   map.chunks_mut(12).enumerate().for_each(|(i, r)| {
+    triangulate_cell(r, i, width, height, elevation, options);
+  })
+}
+
+/// Rewrites the cells of a `triangulate_map` buffer that use any elevation
+/// sample in the inclusive rectangle `(x0, y0)..=(x1, y1)`. A sample is a
+/// corner of the two cells left of it on its own row and of up to two cells on
+/// the row above. Returns the dirty byte range of `map`, or `None` when the
+/// rectangle lies outside the map. With `BorderMode::Wrap` an edit on the
+/// edge also moves cells on the opposite side, so the whole map is redone.
+pub fn update_region(map: &mut [f32], width: usize, height: usize, elevation: &[u8], region: (usize, usize, usize, usize), options: &TessellationOptions) -> Option<(usize, usize)> {
+  let (x0, y0, x1, y1) = region;
+  if x0 > x1 || y0 > y1 || x0 >= width || y0 >= height {
+    return None;
+  }
+
+  if options.border_mode == BorderMode::Wrap && (x0 == 0 || y0 == 0 || x1 + 1 >= width || y1 + 1 >= height) {
+    for (i, r) in map[..width * height * 12].chunks_mut(12).enumerate() {
+      triangulate_cell(r, i, width, height, elevation, options);
+    }
+    return Some((0, width * height * 12 * 4));
+  }
+
+  let cx0 = x0.saturating_sub(1);
+  let cx1 = (x1 + 1).min(width - 1);
+  let cy0 = y0.saturating_sub(1);
+  let cy1 = y1.min(height - 1);
+
+  for y in cy0..=cy1 {
+    for x in cx0..=cx1 {
+      let i = y * width + x;
+      triangulate_cell(&mut map[12 * i..12 * (i + 1)], i, width, height, elevation, options);
+    }
+  }
+
+  let first = cy0 * width + cx0;
+  let last = cy1 * width + cx1;
+
+  Some((first * 12 * 4, (last + 1) * 12 * 4))
+}
+
+/// A hit on the tessellated map: cell index, triangle within the cell (0 or 1,
+/// in `triangulate_map` order) and barycentric weights of its three vertices.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pick {
+  pub cell: usize,
+  pub triangle: usize,
+  pub barycentric: [f32; 3],
+}
+
+pub fn barycentric(p: (f32, f32), a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> Option<[f32; 3]> {
+  let det = (b.1 - c.1) * (a.0 - c.0) + (c.0 - b.0) * (a.1 - c.1);
+  if det.abs() < 1e-6 {
+    return None;
+  }
+
+  let l0 = ((b.1 - c.1) * (p.0 - c.0) + (c.0 - b.0) * (p.1 - c.1)) / det;
+  let l1 = ((c.1 - a.1) * (p.0 - c.0) + (a.0 - c.0) * (p.1 - c.1)) / det;
+
+  Some([l0, l1, 1.0 - l0 - l1])
+}
+
+/// Finds the triangle of the tessellated map under `(x, y)` in mesh
+/// coordinates. Rows further down are drawn later and cover the rows behind
+/// them, so the hit on the lowest row wins.
+pub fn pick(width: usize, height: usize, elevation: &[u8], x: f32, y: f32, options: &TessellationOptions) -> Option<Pick> {
+  const EPSILON: f32 = -1e-5;
+
+  let cs = options.cell_size;
+  let lift = options.max_lift();
+
+  // Rows whose lifted triangles can reach y, and columns whose triangles can
+  // reach x including the half-cell offset of odd rows.
+  let row0 = (((y - lift) / cs - 2.0) / 2.0).floor().max(0.0) as usize;
+  let row1 = ((y + lift) / cs / 2.0).floor();
+  let col0 = ((x / cs - 3.0) / 2.0).floor().max(0.0) as usize;
+  let col1 = ((x / cs + 1.0) / 2.0).ceil();
+  if row1 < 0.0 || col1 < 0.0 {
+    return None;
+  }
+  let row1 = (row1 as usize).min(height.saturating_sub(1));
+  let col1 = (col1 as usize).min(width.saturating_sub(1));
+
+  let mut r = [0.0f32; 12];
+
+  for row in (row0..=row1).rev() {
+    let mut hit = None;
+
+    for col in col0..=col1 {
+      let i = row * width + col;
+      triangulate_cell(&mut r, i, width, height, elevation, options);
+
+      for t in 0..2 {
+        let v = |k: usize| (r[6 * t + 2 * k], r[6 * t + 2 * k + 1]);
+
+        if let Some(b) = barycentric((x, y), v(0), v(1), v(2)) {
+          if b.iter().all(|w| *w >= EPSILON) {
+            hit = Some(Pick { cell: i, triangle: t, barycentric: b });
+          }
+        }
+      }
+    }
+
+    if hit.is_some() {
+      return hit;
+    }
+  }
+
+  None
+}
+
+/// Rectangle of cells tessellated together.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Chunk {
+  pub x: usize,
+  pub y: usize,
+  pub width: usize,
+  pub height: usize,
+}
+
+/// Number of chunk columns and rows when the map is split into
+/// `chunk_size`-cell squares. The last column and row may be narrower.
+pub fn chunk_grid(width: usize, height: usize, chunk_size: usize) -> (usize, usize) {
+  (width.div_ceil(chunk_size), height.div_ceil(chunk_size))
+}
+
+pub fn chunk_at(width: usize, height: usize, chunk_size: usize, cx: usize, cy: usize) -> Option<Chunk> {
+  let (cols, rows) = chunk_grid(width, height, chunk_size);
+  if cx >= cols || cy >= rows {
+    return None;
+  }
+
+  let x = cx * chunk_size;
+  let y = cy * chunk_size;
+
+  Some(Chunk { x, y, width: chunk_size.min(width - x), height: chunk_size.min(height - y) })
+}
+
+/// Same as `triangulate_map`, restricted to the cells of `chunk`, row by row.
+pub fn triangulate_chunk(width: usize, height: usize, elevation: &[u8], chunk: &Chunk, options: &TessellationOptions) -> Vec<f32> {
+  let mut tris = vec![0.0; chunk.width * chunk.height * 12];
+
+  tris.chunks_mut(12).enumerate().for_each(|(k, r)| {
+    let i = (chunk.y + k / chunk.width) * width + chunk.x + k % chunk.width;
+    triangulate_cell(r, i, width, height, elevation, options);
+  });
+
+  tris
+}
+
+/// Bounding box `(x0, y0, x1, y1)` of the tessellated chunk in mesh
+/// coordinates. Elevation lifts vertices towards smaller y, so the top edge
+/// moves up by the highest sample the chunk touches; negative height scales
+/// push the bottom edge down instead.
+pub fn chunk_bounds(width: usize, height: usize, elevation: &[u8], chunk: &Chunk, options: &TessellationOptions) -> (f32, f32, f32, f32) {
+  let mut lift = 0.0f32;
+  let mut sink = 0.0f32;
+  for y in chunk.y..chunk.y + chunk.height {
+    for x in chunk.x..chunk.x + chunk.width {
+      for e in elevation_at(y * width + x, width, height, elevation, options).iter() {
+        lift = lift.max(*e);
+        sink = sink.min(*e);
+      }
+    }
+  }
+
+  let cs = options.cell_size;
+
+  (
+    cs * (2.0 * chunk.x as f32 - 1.0),
+    cs * 2.0 * chunk.y as f32 - lift,
+    cs * (2.0 * (chunk.x + chunk.width) as f32 + 1.0),
+    cs * 2.0 * (chunk.y + chunk.height) as f32 - sink,
+  )
+}
+
+/// Row-major indices of the chunks whose bounds intersect the camera
+/// rectangle `(x0, y0)..(x1, y1)` in mesh coordinates.
+pub fn visible_chunks(width: usize, height: usize, elevation: &[u8], chunk_size: usize, view: (f32, f32, f32, f32), options: &TessellationOptions) -> Vec<usize> {
+  let (cols, rows) = chunk_grid(width, height, chunk_size);
+  let mut visible = vec![];
+
+  for cy in 0..rows {
+    for cx in 0..cols {
+      let chunk = chunk_at(width, height, chunk_size, cx, cy).unwrap();
+      let b = chunk_bounds(width, height, elevation, &chunk, options);
+
+      if b.0 <= view.2 && b.2 >= view.0 && b.1 <= view.3 && b.3 >= view.1 {
+        visible.push(cy * cols + cx);
+      }
+    }
+  }
+
+  visible
+}
+
+/// Vertex columns of the indexed mesh. Cells on even rows reach one sample to
+/// the left of column 0, cells on the last column one sample past it.
+#[inline]
+pub fn vertex_grid_width(width: usize) -> usize {
+  width + 2
+}
+
+#[inline]
+pub fn vertex_count(width: usize, height: usize) -> usize {
+  vertex_grid_width(width) * (height + 1)
+}
+
+/// Indices into the indexed vertex buffer of the four samples `elevation_at`
+/// returns for cell `i`, in the same order.
+pub fn cell_vertices(i: usize, width: usize) -> [usize; 4] {
+  let vw = vertex_grid_width(width);
+  let x = i % width + 1;
+  let y = i / width;
+  let odd = y % 2;
+
+  [
+    y * vw + x,
+    (y + 1) * vw + x + odd,
+    (y + 1) * vw + x + odd - 1,
+    y * vw + x + 1,
+  ]
+}
+
+/// Ground position of vertex `v` of the indexed mesh, before elevation.
+pub fn vertex_ground_position(v: usize, width: usize, options: &TessellationOptions) -> (f32, f32) {
+  let vw = vertex_grid_width(width);
+  let sx = (v % vw) as f32 - 1.0;
+  let sy = v / vw;
+
+  (options.cell_size * (2.0 * sx + (sy % 2) as f32), options.cell_size * 2.0 * sy as f32)
+}
+
+/// Whether any cell that is not flattened by `BorderMode::Zero` has sample
+/// `(sx, sy)` as a corner: the cell it starts, the cell left of it and the two
+/// cells on the row above.
+fn sample_in_interior(sx: isize, sy: isize, w: usize, h: usize) -> bool {
+  let odd = (sy - 1).rem_euclid(2);
+  let interior = |x: isize, y: isize| x >= 1 && y >= 1 && x + 2 <= w as isize && y + 2 <= h as isize;
+
+  interior(sx, sy) || interior(sx - 1, sy) || interior(sx - odd, sy - 1) || interior(sx + 1 - odd, sy - 1)
+}
+
+/// Height of every vertex of the indexed mesh, in `elevation_at` units. Each
+/// vertex takes the height of its sample. With `BorderMode::Zero` a border
+/// cell shares vertices with the interior, so only vertices that belong to
+/// border cells alone are flattened to 0.
+pub fn vertex_heights(width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) -> Vec<f32> {
+  let vw = vertex_grid_width(width);
+
+  (0..vertex_count(width, height)).map(|v| {
+    let sx = (v % vw) as isize - 1;
+    let sy = (v / vw) as isize;
+
+    if options.border_mode == BorderMode::Zero && !sample_in_interior(sx, sy, width, height) {
+      0.0
+    } else {
+      sample_height(sx, sy, width, height, elevation, options)
+    }
+  }).collect()
+}
+
+/// One `(x, y)` vertex per sample of the staggered grid. Cells that
+/// `triangulate_map` does not flatten come out identical; see `vertex_heights`
+/// for the border.
+pub fn triangulate_map_vertices(width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) -> Vec<f32> {
+  let heights = vertex_heights(width, height, elevation, options);
+
+  let mut vertices = vec![0.0f32; 2 * heights.len()];
+  for (v, (r, e)) in vertices.chunks_mut(2).zip(heights.iter()).enumerate() {
+    let (gx, gy) = vertex_ground_position(v, width, options);
+
+    r[0] = gx;
+    r[1] = gy - e;
+  }
+
+  vertices
+}
+
+/// The six indices of cell `i` into `triangulate_map_vertices`, with the
+/// winding of `triangulate_map`.
+fn cell_triangles(i: usize, width: usize) -> [u32; 6] {
+  let v = cell_vertices(i, width);
+
+  [v[0] as u32, v[1] as u32, v[2] as u32, v[0] as u32, v[3] as u32, v[1] as u32]
+}
+
+/// Six indices for every cell, left out ones included, so triangle `t` is
+/// triangle `t % 2` of cell `t / 2` as in `triangulate_map`.
+pub fn cell_indices(width: usize, height: usize) -> Vec<u32> {
+  (0..width * height).flat_map(|i| cell_triangles(i, width).to_vec()).collect()
+}
+
+/// Triangle list over `triangulate_map_vertices` for the cells that are part
+/// of the mesh. Only depends on the map size and the border options.
+pub fn triangulate_map_indices(width: usize, height: usize, options: &TessellationOptions) -> Vec<u32> {
+  (0..width * height)
+    .filter(|&i| cell_emitted(i, width, height, options))
+    .flat_map(|i| cell_triangles(i, width).to_vec())
+    .collect()
+}
+
+/// Smooth `(nx, ny, nz)` normal per vertex of the indexed mesh, averaged from
+/// the area weighted normals of the surrounding triangles. x points right, y
+/// down the rows of the map and z up, so flat ground is `(0, 0, 1)`.
+pub fn triangulate_map_normals(width: usize, height: usize, elevation: &[u8], options: &TessellationOptions) -> Vec<f32> {
+  let heights = vertex_heights(width, height, elevation, options);
+  let mut normals = vec![0.0f32; 3 * heights.len()];
+
+  // Ground position of a vertex; elevation goes into z instead of shifting y.
+  let position = |v: usize| {
+    let (gx, gy) = vertex_ground_position(v, width, options);
+    [gx, gy, heights[v]]
+  };
+
+  for t in triangulate_map_indices(width, height, options).chunks(3) {
+    let p0 = position(t[0] as usize);
+    let p1 = position(t[1] as usize);
+    let p2 = position(t[2] as usize);
+
+    let a = [p1[0] - p0[0], p1[1] - p0[1], p1[2] - p0[2]];
+    let b = [p2[0] - p0[0], p2[1] - p0[1], p2[2] - p0[2]];
+    let n = [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+
+    for v in t.iter() {
+      for (c, d) in normals[3 * *v as usize..].iter_mut().zip(n.iter()) {
+        *c += d;
+      }
+    }
+  }
+
+  for n in normals.chunks_mut(3) {
+    let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+    if len > 0.0 {
+      n[0] /= len;
+      n[1] /= len;
+      n[2] /= len;
+    } else {
+      n[2] = 1.0;
+    }
+  }
+
+  normals
+}
+
+/// Lambert light per normal for a sun direction pointing towards the sun,
+/// lifted by `ambient` so slopes facing away are not black.
+pub fn hillshade(normals: &[f32], sun: [f32; 3], ambient: f32) -> Vec<f32> {
+  let len = (sun[0] * sun[0] + sun[1] * sun[1] + sun[2] * sun[2]).sqrt();
+  let sun = if len > 0.0 { [sun[0] / len, sun[1] / len, sun[2] / len] } else { [0.0, 0.0, 1.0] };
+
+  normals.chunks(3).map(|n| {
+    let diffuse = (n[0] * sun[0] + n[1] * sun[1] + n[2] * sun[2]).max(0.0);
+    ambient + (1.0 - ambient) * diffuse
+  }).collect()
+}
+
+/// Repeats per-vertex attributes of the indexed mesh for the six vertices of
+/// every cell, matching the layout of `triangulate_map`.
+pub fn expand_indexed<T: Copy>(attributes: &[T], components: usize, indices: &[u32]) -> Vec<T> {
+  let mut out = Vec::with_capacity(indices.len() * components);
+  for i in indices {
+    let v = *i as usize * components;
+    out.extend_from_slice(&attributes[v..v + components]);
+  }
+  out
+}
+
+/// Floats per vertex in `triangulate_map_attributes`.
+pub const ATTRIBUTE_COMPONENTS: usize = 5;
+
+/// `TRANSITION_VARIANTS` index that turns the overlay side of the mask towards
+/// each edge of `triangle_neighbours`. The unflipped mask has the overlay on
+/// the upper left; mirroring moves it right or down.
+const EDGE_VARIANTS: [[usize; 3]; 2] = [
+  // A: own B on the upper right, left cell on the upper left, row below.
+  [1, 0, 2],
+  // B: own A on the lower left, right cell on the lower right, row above.
+  [2, 3, 0],
+];
+
+/// Splits a flat `(own, other, mask layer)` list from JS into triples.
+pub fn transition_triples(flat: &[u32]) -> Result<Vec<(u8, u8, u32)>, &'static str> {
+  if !flat.len().is_multiple_of(3) {
+    return Err("transitions must be (own, other, layer) triples.");
+  }
+
+  flat.chunks(3).map(|c| {
+    if c[0] > 0xFF || c[1] > 0xFF {
+      return Err("transition landscape types must fit in a byte.");
+    }
+    Ok((c[0] as u8, c[1] as u8, c[2]))
+  }).collect()
+}
+
+/// Cells sharing an edge with each triangle of cell `i`, paired with which
+/// of their triangles (0 for A, 1 for B) is on the other side.
+fn triangle_neighbours(i: usize, width: usize, height: usize) -> [[Option<(usize, usize)>; 3]; 2] {
+  let x = i % width;
+  let y = i / width;
+  let odd = y % 2;
+
+  let cell = |x: usize, y: usize| if x < width && y < height { Some(y * width + x) } else { None };
+
+  [
+    [
+      Some((i, 1)),
+      x.checked_sub(1).and_then(|x| cell(x, y)).map(|c| (c, 1)),
+      (x + odd).checked_sub(1).and_then(|x| cell(x, y + 1)).map(|c| (c, 1)),
+    ],
+    [
+      Some((i, 0)),
+      cell(x + 1, y).map(|c| (c, 0)),
+      y.checked_sub(1).and_then(|y| cell(x + odd, y)).map(|c| (c, 0)),
+    ],
+  ]
+}
+
+/// Per-vertex `(u, v, layer, transition_layer, variant)` for the six vertices
+/// of every cell, aligned with `triangulate_map`.
+///
+/// `types_a` and `types_b` hold the landscape type of the first and second
+/// triangle of each cell. `texture_layer[t]` is the `create_2d_texture` layer
+/// of type `t`, `u32::MAX` for a type without one. A triangle next to one of
+/// a different type gets the mask layer of the first `(own, other, layer)`
+/// entry of `transitions` for that pair, and `variant` is the
+/// `transition::TRANSITION_VARIANTS` index that orients the mask towards the
+/// shared edge. An `(other, own, layer)` entry is used with the swapped
+/// variant. Both are -1 when there is no transition.
+pub fn triangulate_map_attributes(width: usize, height: usize, types_a: &[u8], types_b: &[u8], texture_layer: &[u32], transitions: &[(u8, u8, u32)], options: &TessellationOptions) -> Result<Vec<f32>, &'static str> {
+  if types_a.len() < width * height || types_b.len() < width * height {
+    return Err("triangulate_map_attributes: terrain type layers are smaller than the map.");
+  }
+
+  let types = [types_a, types_b];
+  let mut attributes = vec![0.0f32; width * height * 6 * ATTRIBUTE_COMPONENTS];
+  let span = 2.0 * options.texture_repeat_cells;
+  let swapped = transition::TRANSITION_VARIANTS.len() / 2;
+
+  for (i, r) in attributes.chunks_mut(6 * ATTRIBUTE_COMPONENTS).enumerate() {
     let x = i % width;
     let y = i / width;
-
     let off = (y % 2) as f32;
-    let elv = elevation_at(i, width, height, elevation);
-    let fx = 2.0 * x as f32;
+    let fx = 2.0 * x as f32 + off;
     let fy = 2.0 * y as f32;
 
-    r[0] = fx + 0.0 + off;
-    r[1] = fy + 0.0 - elv[0];
-    r[2] = fx + 1.0 + off;
-    r[3] = fy + 2.0 - elv[1];
-    r[4] = fx - 1.0 + off;
-    r[5] = fy + 2.0 - elv[2];
-
-    r[6] = fx + 0.0 + off;
-    r[7] = fy + 0.0 - elv[0];
-    r[8] = fx + 2.0 + off;
-    r[9] = fy + 0.0 - elv[3];
-    r[10] = fx + 1.0 + off;
-    r[11] = fy + 2.0 - elv[1];
-  })
+    // Ground positions of the six vertices, as in `triangulate_map`.
+    let corners = [(fx, fy), (fx + 1.0, fy + 2.0), (fx - 1.0, fy + 2.0), (fx, fy), (fx + 2.0, fy), (fx + 1.0, fy + 2.0)];
+    let neighbours = triangle_neighbours(i, width, height);
+
+    for t in 0..2 {
+      let own = types[t][i];
+      let layer = *texture_layer.get(own as usize).filter(|&&l| l != u32::MAX)
+        .ok_or("triangulate_map_attributes: landscape type has no texture layer.")? as f32;
+
+      let (transition, variant) = neighbours[t].iter().enumerate()
+        .filter_map(|(edge, n)| n.map(|(c, nt)| (edge, types[nt][c])))
+        .filter(|(_, other)| *other != own)
+        .find_map(|(edge, other)| {
+          let variant = EDGE_VARIANTS[t][edge];
+          transitions.iter().find(|tr| tr.0 == own && tr.1 == other).map(|tr| (tr.2, variant))
+            .or_else(|| transitions.iter().find(|tr| tr.0 == other && tr.1 == own).map(|tr| (tr.2, variant + swapped)))
+        })
+        .map_or((-1.0, -1.0), |(layer, variant)| (layer as f32, variant as f32));
+
+      for k in 3 * t..3 * t + 3 {
+        let v = &mut r[k * ATTRIBUTE_COMPONENTS..(k + 1) * ATTRIBUTE_COMPONENTS];
+        v[0] = corners[k].0 / span;
+        v[1] = corners[k].1 / span;
+        v[2] = layer;
+        v[3] = transition;
+        v[4] = variant;
+      }
+    }
+  }
+
+  Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ramp(w: usize, h: usize) -> Vec<u8> {
+    (0..w * h).map(|i| (i * 7 % 251) as u8).collect()
+  }
+
+  #[test]
+  fn test_indexed_matches_flat_mesh() {
+    let (w, h) = (6, 5);
+    let elevation = ramp(w, h);
+
+    let mut flat = vec![0.0; w * h * 12];
+    triangulate_map(&mut flat, w, h, &elevation, &TessellationOptions::default());
+    let vertices = triangulate_map_vertices(w, h, &elevation, &TessellationOptions::default());
+    let indices = triangulate_map_indices(w, h, &TessellationOptions::default());
+
+    for i in 0..w * h {
+      let (x, y) = (i % w, i / w);
+      if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+        continue;
+      }
+
+      for k in 0..6 {
+        let v = indices[6 * i + k] as usize;
+        assert_eq!(&vertices[2 * v..2 * v + 2], &flat[12 * i + 2 * k..12 * i + 2 * k + 2], "cell {} vertex {}", i, k);
+      }
+    }
+  }
+
+  #[test]
+  fn test_indexed_heights() {
+    let (w, h) = (5, 5);
+    let mut elevation = ramp(w, h);
+    elevation[2 * w + 2] = 0;
+    let options = TessellationOptions { border_mode: BorderMode::Clamp, ..TessellationOptions::default() };
+
+    // Without flattened cells every cell, border included, matches the flat mesh.
+    let mut flat = vec![0.0; w * h * 12];
+    triangulate_map(&mut flat, w, h, &elevation, &options);
+    let vertices = triangulate_map_vertices(w, h, &elevation, &options);
+    for (k, v) in triangulate_map_indices(w, h, &options).iter().enumerate() {
+      let v = *v as usize;
+      assert_eq!(&vertices[2 * v..2 * v + 2], &flat[2 * k..2 * k + 2], "vertex {}", k);
+    }
+
+    let heights = vertex_heights(w, h, &elevation, &TessellationOptions::default());
+    let vw = vertex_grid_width(w);
+    assert_eq!(heights[2 * vw + 3], 0.0);
+    assert_eq!(heights[vw + 2], elevation[w + 1] as f32 / 16.0);
+    // Row 0 only belongs to flattened border cells.
+    assert!(heights[..vw].iter().all(|e| *e == 0.0));
+  }
+
+  #[test]
+  fn test_indexed_ground_positions() {
+    let (w, h) = (4, 4);
+    let vertices = triangulate_map_vertices(w, h, &vec![0u8; w * h], &TessellationOptions::default());
+
+    let mut flat = vec![0.0; w * h * 12];
+    triangulate_map(&mut flat, w, h, &vec![0u8; w * h], &TessellationOptions::default());
+    let indices = triangulate_map_indices(w, h, &TessellationOptions::default());
+
+    for (k, v) in indices.iter().enumerate() {
+      let v = *v as usize;
+      assert_eq!(&vertices[2 * v..2 * v + 2], &flat[2 * k..2 * k + 2]);
+    }
+  }
+
+  #[test]
+  fn test_normals() {
+    let (w, h) = (5, 5);
+    let flat = triangulate_map_normals(w, h, &vec![0u8; w * h], &TessellationOptions::default());
+    for n in flat.chunks(3) {
+      assert_eq!(n, &[0.0, 0.0, 1.0]);
+    }
+
+    // A single raised sample tilts its left neighbour's normal to the left.
+    let mut elevation = vec![0u8; w * h];
+    elevation[2 * w + 2] = 64;
+    let normals = triangulate_map_normals(w, h, &elevation, &TessellationOptions::default());
+    let left = cell_vertices(2 * w + 1, w)[0];
+    assert!(normals[3 * left] < 0.0);
+
+    let light = hillshade(&normals, [0.0, 0.0, 1.0], 0.25);
+    assert_eq!(light[0], 1.0);
+    assert!(light[left] < 1.0 && light[left] > 0.25);
+  }
+
+  #[test]
+  fn test_triangle_neighbours_share_edges() {
+    let (w, h) = (5, 4);
+    let indices = triangulate_map_indices(w, h, &TessellationOptions::default());
+    let edges = |i: usize, t: usize| {
+      let tri = &indices[6 * i + 3 * t..6 * i + 3 * t + 3];
+      let mut e: Vec<(u32, u32)> = (0..3).map(|k| (tri[k].min(tri[(k + 1) % 3]), tri[k].max(tri[(k + 1) % 3]))).collect();
+      e.sort();
+      e
+    };
+
+    for i in 0..w * h {
+      for t in 0..2 {
+        for n in triangle_neighbours(i, w, h)[t].iter().filter_map(|n| *n) {
+          let shared = edges(i, t).iter().filter(|e| edges(n.0, n.1).contains(e)).count();
+          assert_eq!(shared, 1, "cell {} triangle {} and {:?}", i, t, n);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_attributes_transitions() {
+    let (w, h) = (3, 3);
+    let types_a = vec![0u8; 9];
+    let mut types_b = vec![0u8; 9];
+    types_b[4] = 1;
+
+    let options = TessellationOptions::default();
+    let attributes = triangulate_map_attributes(w, h, &types_a, &types_b, &[5, 6], &[(0, 1, 9)], &options).unwrap();
+    let vertex = |i: usize, k: usize| &attributes[(6 * i + k) * ATTRIBUTE_COMPONENTS..(6 * i + k + 1) * ATTRIBUTE_COMPONENTS];
+
+    // A of the middle cell borders its own B on the upper right.
+    assert_eq!(vertex(4, 0)[2..], [5.0, 9.0, 1.0]);
+    // B of the middle cell only has the (0, 1) entry, so its mask is swapped.
+    assert_eq!(vertex(4, 3)[2..], [6.0, 9.0, 6.0]);
+    // A of the cell to the right meets it across its upper left edge, A of
+    // the cell above across its bottom edge.
+    assert_eq!(vertex(5, 0)[2..], [5.0, 9.0, 0.0]);
+    assert_eq!(vertex(2, 0)[2..], [5.0, 9.0, 2.0]);
+    assert_eq!(vertex(0, 0)[2..], [5.0, -1.0, -1.0]);
+    assert_eq!(vertex(0, 4)[..2], [2.0 / 16.0, 0.0]);
+
+    let options = TessellationOptions { texture_repeat_cells: 4.0, ..options };
+    let attributes = triangulate_map_attributes(w, h, &types_a, &types_b, &[5, 6], &[], &options).unwrap();
+    assert_eq!(attributes[4 * ATTRIBUTE_COMPONENTS], 2.0 / 8.0);
+    assert!(triangulate_map_attributes(w, h, &types_a, &types_b, &[5, u32::MAX], &[], &options).is_err());
+
+    assert_eq!(transition_triples(&[0, 1, 9, 1, 0, 10]), Ok(vec![(0, 1, 9), (1, 0, 10)]));
+    assert!(transition_triples(&[0, 1]).is_err());
+    assert!(transition_triples(&[256, 1, 9]).is_err());
+  }
+
+  #[test]
+  fn test_chunks_match_full_map() {
+    let (w, h) = (7, 5);
+    let elevation = ramp(w, h);
+    let mut flat = vec![0.0; w * h * 12];
+    triangulate_map(&mut flat, w, h, &elevation, &TessellationOptions::default());
+
+    assert_eq!(chunk_grid(w, h, 3), (3, 2));
+
+    let chunk = chunk_at(w, h, 3, 2, 1).unwrap();
+    assert_eq!(chunk, Chunk { x: 6, y: 3, width: 1, height: 2 });
+
+    let tris = triangulate_chunk(w, h, &elevation, &chunk, &TessellationOptions::default());
+    assert_eq!(&tris[..12], &flat[(3 * w + 6) * 12..(3 * w + 7) * 12]);
+    assert_eq!(&tris[12..], &flat[(4 * w + 6) * 12..(4 * w + 7) * 12]);
+  }
+
+  #[test]
+  fn test_visible_chunks_allow_for_elevation() {
+    let (w, h) = (8, 8);
+    let mut elevation = vec![0u8; w * h];
+
+    assert_eq!(visible_chunks(w, h, &elevation, 4, (0.0, 0.0, 4.0, 4.0), &TessellationOptions::default()), vec![0]);
+    assert_eq!(visible_chunks(w, h, &elevation, 4, (0.0, 0.0, 8.0, 4.0), &TessellationOptions::default()), vec![0, 1]);
+
+    // A tall sample in the lower chunk reaches up into the view.
+    elevation[5 * w + 2] = 160;
+    assert_eq!(visible_chunks(w, h, &elevation, 4, (0.0, 0.0, 4.0, 4.0), &TessellationOptions::default()), vec![0, 2]);
+  }
+
+  #[test]
+  fn test_update_region() {
+    let (w, h) = (6, 6);
+    let mut elevation = ramp(w, h);
+    let mut map = vec![0.0; w * h * 12];
+    triangulate_map(&mut map, w, h, &elevation, &TessellationOptions::default());
+
+    for &(sx, sy) in [(3usize, 3usize), (2, 2), (4, 1)].iter() {
+      elevation[sy * w + sx] = elevation[sy * w + sx].wrapping_add(90);
+      let before = map.clone();
+      let (start, end) = update_region(&mut map, w, h, &elevation, (sx, sy, sx, sy), &TessellationOptions::default()).unwrap();
+
+      let mut expected = vec![0.0; w * h * 12];
+      triangulate_map(&mut expected, w, h, &elevation, &TessellationOptions::default());
+      assert_eq!(map, expected);
+
+      for (k, (a, b)) in before.iter().zip(expected.iter()).enumerate() {
+        if a != b {
+          assert!(start <= 4 * k && 4 * k < end, "sample ({}, {}) float {}", sx, sy, k);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_pick_flat_and_occluded() {
+    let (w, h) = (6, 6);
+    let mut elevation = vec![0u8; w * h];
+
+    let hit = pick(w, h, &elevation, 4.5, 4.5, &TessellationOptions::default()).unwrap();
+    assert_eq!((hit.cell, hit.triangle), (2 * w + 2, 1));
+    assert!((hit.barycentric.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+
+    assert_eq!(pick(w, h, &elevation, -5.0, 3.0, &TessellationOptions::default()), None);
+
+    // Lifting row 4 by 4 units puts its cells in front of the point.
+    for x in 0..w {
+      elevation[4 * w + x] = 64;
+    }
+    let hit = pick(w, h, &elevation, 4.5, 4.5, &TessellationOptions::default()).unwrap();
+    assert_eq!(hit.cell / w, 4);
+  }
+
+  #[test]
+  fn test_tessellation_options() {
+    let (w, h) = (4, 4);
+    let elevation = vec![32u8; w * h];
+    let mut map = vec![0.0; w * h * 12];
+
+    // Border cells are flat by default, and follow the map with Clamp.
+    triangulate_map(&mut map, w, h, &elevation, &TessellationOptions::default());
+    assert_eq!(&map[..2], &[0.0, 0.0]);
+
+    let options = TessellationOptions { height_scale: 0.5, cell_size: 2.0, border_mode: BorderMode::Clamp, ..TessellationOptions::default() };
+    triangulate_map(&mut map, w, h, &elevation, &options);
+    assert_eq!(&map[..4], &[0.0, -16.0, 2.0, -12.0]);
+
+    let options = TessellationOptions { border_mode: BorderMode::Wrap, ..TessellationOptions::default() };
+    assert_eq!(sample_height(-1, 0, w, h, &(0..16).collect::<Vec<u8>>(), &options), 3.0 / 16.0);
+
+    let options = TessellationOptions { emit_border: false, ..TessellationOptions::default() };
+    triangulate_map(&mut map, w, h, &elevation, &options);
+    assert!(map[..12].chunks(2).all(|v| v == &map[..2]));
+    let indices = triangulate_map_indices(w, h, &options);
+    assert_eq!(indices.len(), 4 * 6);
+    assert_eq!(&indices[..6], &cell_indices(w, h)[5 * 6..6 * 6]);
+
+    // Skip leaves out the last row, the last column and column 0 of even rows.
+    let options = TessellationOptions { border_mode: BorderMode::Skip, ..TessellationOptions::default() };
+    let emitted: Vec<usize> = (0..w * h).filter(|&i| cell_emitted(i, w, h, &options)).collect();
+    assert_eq!(emitted, vec![1, 2, 4, 5, 6, 9, 10]);
+    assert_eq!(triangulate_map_indices(w, h, &options).len(), emitted.len() * 6);
+  }
 }
@@ -0,0 +1,122 @@
+use crate::pcx;
+
+/// Orientation of the transition mask and which landscape sits on top. The
+/// triangle grid is symmetric under horizontal and vertical mirroring, so those
+/// are the orientations a renderer can pick between; quarter turns are not.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransitionVariant {
+  pub flip_x: bool,
+  pub flip_y: bool,
+  /// Swaps the two landscapes, i.e. inverts the mask.
+  pub swap: bool,
+}
+
+/// Layer order of the texture array produced by `transition_set`.
+pub const TRANSITION_VARIANTS: [TransitionVariant; 8] = [
+  TransitionVariant { flip_x: false, flip_y: false, swap: false },
+  TransitionVariant { flip_x: true, flip_y: false, swap: false },
+  TransitionVariant { flip_x: false, flip_y: true, swap: false },
+  TransitionVariant { flip_x: true, flip_y: true, swap: false },
+  TransitionVariant { flip_x: false, flip_y: false, swap: true },
+  TransitionVariant { flip_x: true, flip_y: false, swap: true },
+  TransitionVariant { flip_x: false, flip_y: true, swap: true },
+  TransitionVariant { flip_x: true, flip_y: true, swap: true },
+];
+
+#[inline]
+fn blend(a: u8, b: u8, t: u8) -> u8 {
+  ((a as u32 * (255 - t as u32) + b as u32 * t as u32 + 127) / 255) as u8
+}
+
+/// Blends two RGBA textures of the same size. Where `mask` is 0 the result is
+/// `base`, where it is 0xFF the result is `overlay`.
+pub fn blend_transition(w: usize, h: usize, base: &[u8], overlay: &[u8], mask: &[u8], variant: &TransitionVariant, out: &mut [u8]) {
+  for y in 0..h {
+    let my = if variant.flip_y { h - 1 - y } else { y };
+
+    for x in 0..w {
+      let mx = if variant.flip_x { w - 1 - x } else { x };
+      let t = if variant.swap { 255 - mask[my * w + mx] } else { mask[my * w + mx] };
+      let p = 4 * (y * w + x);
+
+      for c in p..p + 3 {
+        out[c] = blend(base[c], overlay[c], t);
+      }
+      out[p + 3] = 0xFF;
+    }
+  }
+}
+
+/// Length of the `transition_set` output for these images, sized from the PCX
+/// header of the base texture.
+pub fn transition_set_len(base_pcx: &[u8]) -> Result<usize, pcx::PcxError> {
+  let (w, h) = pcx::get_dimensions(base_pcx)?;
+
+  Ok(w * h * 4 * TRANSITION_VARIANTS.len())
+}
+
+/// Decodes two landscape textures and a transition mask and renders every
+/// entry of `TRANSITION_VARIANTS` into consecutive RGBA layers of `out`.
+pub fn transition_set(base_pcx: &[u8], overlay_pcx: &[u8], mask_pcx: &[u8], mask_alpha: &pcx::MaskAlpha, out: &mut [u8]) -> Result<(), pcx::PcxError> {
+  let (w, h) = pcx::get_dimensions(base_pcx)?;
+  let len = w * h * 4;
+
+  if pcx::get_dimensions(overlay_pcx)? != (w, h) || pcx::get_dimensions(mask_pcx)? != (w, h) {
+    return Err(pcx::PcxError::Invalid("Transition textures and mask differ in size."));
+  }
+  if out.len() < len * TRANSITION_VARIANTS.len() {
+    return Err(pcx::PcxError::OutputTooSmall);
+  }
+
+  let mut base = vec![0u8; len];
+  let mut overlay = vec![0u8; len];
+  pcx::pcx_read(base_pcx, &mut base, None, &pcx::MaskAlpha::default())?;
+  pcx::pcx_read(overlay_pcx, &mut overlay, None, &pcx::MaskAlpha::default())?;
+  let mask = pcx::pcx_read_alpha(mask_pcx, mask_alpha)?;
+
+  for (variant, layer) in TRANSITION_VARIANTS.iter().zip(out.chunks_mut(len)) {
+    blend_transition(w, h, &base, &overlay, &mask, variant, layer);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn solid(value: u8) -> Vec<u8> {
+    pcx::pcx_write(2, 2, &[0; 4], &[value; 768]).unwrap()
+  }
+
+  #[test]
+  fn test_transition_variants() {
+    let ramp: Vec<u8> = (0..768).map(|i| (i / 3) as u8).collect();
+    // Only the top right pixel of the mask is fully overlay.
+    let mask = pcx::pcx_write(2, 2, &[0, 255, 0, 0], &ramp).unwrap();
+    let mut out = vec![0u8; 2 * 2 * 4 * TRANSITION_VARIANTS.len()];
+
+    transition_set(&solid(0), &solid(200), &mask, &pcx::MaskAlpha::default(), &mut out).unwrap();
+
+    for (variant, layer) in TRANSITION_VARIANTS.iter().zip(out.chunks(16)) {
+      let x = if variant.flip_x { 0 } else { 1 };
+      let y = if variant.flip_y { 1 } else { 0 };
+
+      for i in 0..4 {
+        let overlay = (i == y * 2 + x) != variant.swap;
+        assert_eq!(layer[4 * i], if overlay { 200 } else { 0 }, "{:?} pixel {}", variant, i);
+        assert_eq!(layer[4 * i + 3], 0xFF);
+      }
+    }
+  }
+
+  #[test]
+  fn test_transition_output_too_small() {
+    let mut out = vec![0u8; 2 * 2 * 4 * TRANSITION_VARIANTS.len() - 1];
+
+    assert_eq!(
+      transition_set(&solid(0), &solid(200), &solid(255), &pcx::MaskAlpha::default(), &mut out),
+      Err(pcx::PcxError::OutputTooSmall)
+    );
+  }
+}
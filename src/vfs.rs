@@ -0,0 +1,310 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
+
+use wasm_bindgen::prelude::*;
+
+#[cfg(feature = "unverified-formats")]
+use crate::lib_archive::LibArchive;
+
+/// Lower case, backslash separated, without leading separators, which is how
+/// the game compares paths. Empty and `.` components are dropped and `..`
+/// removes the component before it, never climbing above the root, so a
+/// normalized path cannot leave the directory it is resolved against.
+pub fn normalize_path(path: &str) -> String {
+  let mut parts: Vec<String> = vec![];
+
+  for part in path.split(['\\', '/']) {
+    match part {
+      "" | "." => {},
+      ".." => { parts.pop(); },
+      _ => parts.push(part.to_ascii_lowercase()),
+    }
+  }
+
+  let mut out = parts.join("\\");
+  if !out.is_empty() && path.ends_with(['\\', '/']) {
+    out.push('\\');
+  }
+  out
+}
+
+/// Matches a normalized path against a normalized pattern. `?` matches one
+/// character and `*` any run of characters within a path component; `**`
+/// also crosses separators.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+  fn matches(p: &[u8], s: &[u8]) -> bool {
+    match p.first() {
+      None => s.is_empty(),
+      Some(b'*') if p.get(1) == Some(&b'*') => (0..=s.len()).any(|k| matches(&p[2..], &s[k..])),
+      Some(b'*') => {
+        let run = s.iter().position(|&c| c == b'\\').unwrap_or(s.len());
+        (0..=run).any(|k| matches(&p[1..], &s[k..]))
+      },
+      Some(b'?') => !s.is_empty() && s[0] != b'\\' && matches(&p[1..], &s[1..]),
+      Some(c) => s.first() == Some(c) && matches(&p[1..], &s[1..]),
+    }
+  }
+
+  matches(pattern.as_bytes(), path.as_bytes())
+}
+
+/// One source of files. Paths are stored normalized, see `normalize_path`.
+enum Mount {
+  #[cfg(feature = "unverified-formats")]
+  Archive(LibArchive),
+  Memory(HashMap<String, Vec<u8>>),
+  /// Loose files below a native directory, looked up case-insensitively.
+  #[cfg(not(target_arch = "wasm32"))]
+  Directory(PathBuf),
+}
+
+/// The file below `root` at a normalized `path`. Parent and current
+/// directory components are refused so the lookup stays below `root`.
+#[cfg(not(target_arch = "wasm32"))]
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+  let mut cur = root.to_path_buf();
+
+  for component in path.split('\\').filter(|c| !c.is_empty()) {
+    if component == "." || component == ".." {
+      return None;
+    }
+
+    let exact = cur.join(component);
+    cur = if exact.exists() {
+      exact
+    } else {
+      std::fs::read_dir(&cur).ok()?
+        .filter_map(|e| e.ok())
+        .find(|e| e.file_name().to_string_lossy().to_ascii_lowercase() == component)?
+        .path()
+    };
+  }
+
+  if cur.is_file() { Some(cur) } else { None }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn walk(dir: &Path, prefix: &str, out: &mut Vec<String>) {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return,
+  };
+
+  for entry in entries.filter_map(|e| e.ok()) {
+    let name = format!("{}{}", prefix, entry.file_name().to_string_lossy().to_ascii_lowercase());
+    let path = entry.path();
+
+    if path.is_dir() {
+      walk(&path, &format!("{}\\", name), out);
+    } else {
+      out.push(name);
+    }
+  }
+}
+
+impl Mount {
+  fn read(&self, path: &str) -> Option<Cow<'_, [u8]>> {
+    match self {
+      #[cfg(feature = "unverified-formats")]
+      Mount::Archive(lib) => lib.open(path).map(Cow::Borrowed),
+      Mount::Memory(files) => files.get(path).map(|data| Cow::Borrowed(&data[..])),
+      #[cfg(not(target_arch = "wasm32"))]
+      Mount::Directory(root) => resolve(root, path).and_then(|p| std::fs::read(p).ok()).map(Cow::Owned),
+    }
+  }
+
+  fn contains(&self, path: &str) -> bool {
+    match self {
+      #[cfg(feature = "unverified-formats")]
+      Mount::Archive(lib) => lib.entry(path).is_some(),
+      Mount::Memory(files) => files.contains_key(path),
+      #[cfg(not(target_arch = "wasm32"))]
+      Mount::Directory(root) => resolve(root, path).is_some(),
+    }
+  }
+
+  fn paths(&self) -> Vec<String> {
+    match self {
+      #[cfg(feature = "unverified-formats")]
+      Mount::Archive(lib) => lib.keys().to_vec(),
+      Mount::Memory(files) => files.keys().cloned().collect(),
+      #[cfg(not(target_arch = "wasm32"))]
+      Mount::Directory(root) => {
+        let mut out = vec![];
+        walk(root, "", &mut out);
+        out
+      },
+    }
+  }
+}
+
+/// Game paths resolved over several mounted sources, the way the game lays
+/// mod directories over its data archives. Later mounts take priority.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Vfs {
+  mounts: Vec<Mount>,
+}
+
+impl Vfs {
+  #[cfg(feature = "unverified-formats")]
+  pub fn mount_archive(&mut self, data: Vec<u8>) -> Result<(), &'static str> {
+    self.mounts.push(Mount::Archive(LibArchive::parse(data)?));
+    Ok(())
+  }
+
+  pub fn mount_files(&mut self, files: Vec<(String, Vec<u8>)>) {
+    self.mounts.push(Mount::Memory(files.into_iter().map(|(p, d)| (normalize_path(&p), d)).collect()));
+  }
+
+  #[cfg(not(target_arch = "wasm32"))]
+  pub fn mount_directory<P: Into<PathBuf>>(&mut self, root: P) {
+    self.mounts.push(Mount::Directory(root.into()));
+  }
+
+  /// Contents of `path` from the mount with the highest priority holding it.
+  pub fn read(&self, path: &str) -> Option<Cow<'_, [u8]>> {
+    let path = normalize_path(path);
+    self.mounts.iter().rev().find_map(|m| m.read(&path))
+  }
+
+  /// Whether any mount holds `path`, without reading it.
+  pub fn exists(&self, path: &str) -> bool {
+    let path = normalize_path(path);
+    self.mounts.iter().any(|m| m.contains(&path))
+  }
+
+  /// Every path in any mount, normalized and sorted.
+  pub fn list(&self) -> Vec<String> {
+    let mut paths: Vec<String> = self.mounts.iter().flat_map(|m| m.paths()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+  }
+
+  pub fn glob(&self, pattern: &str) -> Vec<String> {
+    let pattern = normalize_path(pattern);
+    self.list().into_iter().filter(|p| glob_match(&pattern, p)).collect()
+  }
+
+  /// Reads every path, failing on the first one missing.
+  pub fn read_all(&self, paths: &[String]) -> Result<Vec<Cow<'_, [u8]>>, String> {
+    paths.iter().map(|p| self.read(p).ok_or_else(|| format!("File not found: {}", p))).collect()
+  }
+}
+
+#[wasm_bindgen]
+impl Vfs {
+  #[wasm_bindgen(constructor)]
+  pub fn new() -> Vfs {
+    Vfs::default()
+  }
+
+  /// Mounts files concatenated in `data`, `sizes[i]` bytes each, under
+  /// `paths[i]`.
+  #[wasm_bindgen(js_name = mountFiles)]
+  pub fn js_mount_files(&mut self, paths: Box<[JsValue]>, data: &[u8], sizes: &[usize]) -> Result<(), JsValue> {
+    if sizes.len() != paths.len() || sizes.iter().sum::<usize>() != data.len() {
+      return Err(JsValue::from_str("Vfs.mountFiles: sizes do not match the paths and data."));
+    }
+
+    let mut files = Vec::with_capacity(paths.len());
+    let mut offset = 0;
+    for (path, size) in paths.into_vec().into_iter().zip(sizes) {
+      let path = path.as_string().ok_or_else(|| JsValue::from_str("Vfs.mountFiles: paths must be strings."))?;
+      files.push((path, data[offset..offset + size].to_vec()));
+      offset += size;
+    }
+
+    self.mount_files(files);
+    Ok(())
+  }
+
+  #[wasm_bindgen(js_name = read)]
+  pub fn js_read(&self, path: &str) -> Option<Box<[u8]>> {
+    self.read(path).map(|data| data.into_owned().into_boxed_slice())
+  }
+
+  #[wasm_bindgen(js_name = exists)]
+  pub fn js_exists(&self, path: &str) -> bool {
+    self.exists(path)
+  }
+
+  #[wasm_bindgen(js_name = list)]
+  pub fn js_list(&self) -> Box<[JsValue]> {
+    self.list().iter().map(|p| JsValue::from_str(p)).collect::<Vec<_>>().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = glob)]
+  pub fn js_glob(&self, pattern: &str) -> Box<[JsValue]> {
+    self.glob(pattern).iter().map(|p| JsValue::from_str(p)).collect::<Vec<_>>().into_boxed_slice()
+  }
+}
+
+#[cfg(feature = "unverified-formats")]
+#[wasm_bindgen]
+impl Vfs {
+  #[wasm_bindgen(js_name = mountArchive)]
+  pub fn js_mount_archive(&mut self, data: Box<[u8]>) -> Result<(), JsValue> {
+    self.mount_archive(data.into_vec()).map_err(JsValue::from_str)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_vfs_priority_and_paths() {
+    let mut vfs = Vfs::new();
+    vfs.mount_files(vec![
+      ("data\\engine2d\\bin\\textures\\gras.pcx".to_string(), b"base grass".to_vec()),
+      ("data\\engine2d\\bin\\textures\\sand.pcx".to_string(), b"base sand".to_vec()),
+    ]);
+    vfs.mount_files(vec![("Data/Engine2D/Bin/Textures/Gras.pcx".to_string(), b"mod grass".to_vec())]);
+
+    assert_eq!(vfs.read("data\\engine2d\\bin\\textures\\GRAS.PCX").as_deref(), Some(&b"mod grass"[..]));
+    assert_eq!(vfs.read("/data/engine2d/bin/textures/sand.pcx").as_deref(), Some(&b"base sand"[..]));
+    assert!(!vfs.exists("data\\engine2d\\bin\\textures\\snow.pcx"));
+    assert!(vfs.exists("data\\engine2d\\..\\engine2d\\.\\bin\\textures\\sand.pcx"));
+    assert_eq!(normalize_path("..\\..\\Data//Mods\\"), "data\\mods\\");
+    assert_eq!(vfs.glob("data\\**.pcx").len(), 2);
+    assert!(vfs.read_all(&["data\\missing.pcx".to_string()]).is_err());
+  }
+
+  #[cfg(feature = "unverified-formats")]
+  #[test]
+  fn test_vfs_archive_mount() {
+    let mut builder = crate::lib_archive::LibBuilder::new();
+    builder.add("data\\engine2d\\bin\\textures\\gras.pcx", b"base grass".to_vec());
+
+    let mut vfs = Vfs::new();
+    vfs.mount_archive(builder.build().unwrap()).unwrap();
+    vfs.mount_files(vec![("data\\mods\\gras.pcx".to_string(), b"mod grass".to_vec())]);
+
+    assert_eq!(vfs.read("Data/Engine2D/Bin/Textures/Gras.pcx").as_deref(), Some(&b"base grass"[..]));
+    assert!(vfs.exists("data\\engine2d\\bin\\textures\\gras.pcx"));
+    assert_eq!(vfs.list().len(), 2);
+  }
+
+  #[test]
+  fn test_vfs_directory_mount() {
+    let root = std::env::temp_dir().join(format!("c2-vfs-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("Data").join("Mods")).unwrap();
+    std::fs::write(root.join("Data").join("Mods").join("Gras.PCX"), b"loose grass").unwrap();
+
+    let mut vfs = Vfs::new();
+    vfs.mount_files(vec![("data\\mods\\gras.pcx".to_string(), b"memory grass".to_vec())]);
+    vfs.mount_directory(&root);
+
+    assert_eq!(vfs.read("DATA\\mods\\gras.pcx").as_deref(), Some(&b"loose grass"[..]));
+    assert!(vfs.exists("data\\mods\\gras.pcx"));
+    assert!(!vfs.exists("data\\mods\\snow.pcx"));
+    assert!(!vfs.exists("data\\..\\..\\gras.pcx"));
+    assert_eq!(vfs.list(), vec!["data\\mods\\gras.pcx".to_string()]);
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -0,0 +1,356 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use wasm_bindgen::prelude::*;
+
+use crate::heightfield::Heightfield;
+use crate::tessellate;
+
+/// A piece of coastline inside one triangle, between the crossing points on
+/// two of its edges.
+struct Segment {
+  cell: usize,
+  edges: [(u32, u32); 2],
+  points: [(f32, f32); 2],
+}
+
+/// Coastline, shore distance and water surface analysis for one map. All
+/// positions are ground coordinates of the indexed mesh.
+#[wasm_bindgen]
+pub struct Water {
+  field: Heightfield,
+  indices: Vec<u32>,
+  /// Per vertex of the indexed mesh: positive under water, negative on land.
+  wet: Vec<f32>,
+}
+
+impl Water {
+  /// Water below `level`, in `TessellationOptions::height_scale` units.
+  pub fn below_level(field: &Heightfield, level: f32) -> Water {
+    let wet = field.vertex_heights().iter().map(|e| level - e).collect();
+
+    Water { field: field.clone(), indices: tessellate::cell_indices(field.width(), field.height()), wet }
+  }
+
+  /// Water where `flags` is set, one flag per triangle, two per cell in
+  /// `triangulate_map` order.
+  pub fn from_triangles(field: &Heightfield, flags: &[bool]) -> Result<Water, &'static str> {
+    let (w, h) = (field.width(), field.height());
+    if flags.len() < 2 * w * h {
+      return Err("Water: need one water flag per triangle.");
+    }
+
+    // Share of the surrounding triangles that are water, so the 0 isoline
+    // runs between water and land triangles.
+    let indices = tessellate::cell_indices(w, h);
+    let mut counts = vec![(0u32, 0u32); tessellate::vertex_count(w, h)];
+    for (t, tri) in indices.chunks(3).enumerate() {
+      for v in tri.iter() {
+        let c = &mut counts[*v as usize];
+        c.0 += flags[t] as u32;
+        c.1 += 1;
+      }
+    }
+
+    let wet = counts.iter().map(|&(water, total)| if total == 0 { -0.5 } else { water as f32 / total as f32 - 0.5 }).collect();
+
+    Ok(Water { field: field.clone(), indices, wet })
+  }
+
+  fn position(&self, v: u32) -> (f32, f32) {
+    tessellate::vertex_ground_position(v as usize, self.field.width(), self.field.options())
+  }
+
+  fn crossing(&self, a: u32, b: u32) -> (f32, f32) {
+    let (fa, fb) = (self.wet[a as usize], self.wet[b as usize]);
+    let t = fa / (fa - fb);
+    let (pa, pb) = (self.position(a), self.position(b));
+
+    (pa.0 + t * (pb.0 - pa.0), pa.1 + t * (pb.1 - pa.1))
+  }
+
+  /// Marching triangles over the 0 isoline of the wetness field.
+  fn segments(&self) -> Vec<Segment> {
+    let mut segments = vec![];
+
+    for (t, tri) in self.indices.chunks(3).enumerate() {
+      let mut edges = [(0u32, 0u32); 2];
+      let mut points = [(0.0f32, 0.0f32); 2];
+      let mut n = 0;
+
+      for k in 0..3 {
+        let (a, b) = (tri[k], tri[(k + 1) % 3]);
+        if (self.wet[a as usize] > 0.0) != (self.wet[b as usize] > 0.0) {
+          if n < 2 {
+            edges[n] = (a.min(b), a.max(b));
+            points[n] = self.crossing(a, b);
+          }
+          n += 1;
+        }
+      }
+
+      if n == 2 {
+        segments.push(Segment { cell: t / 2, edges, points });
+      }
+    }
+
+    segments
+  }
+
+  /// Coastline polylines. Closed loops repeat their first point at the end;
+  /// open lines start and end on the map border.
+  pub fn coastlines(&self) -> Vec<Vec<(f32, f32)>> {
+    let segments = self.segments();
+
+    let mut by_edge: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for (i, s) in segments.iter().enumerate() {
+      for e in s.edges.iter() {
+        by_edge.entry(*e).or_default().push(i);
+      }
+    }
+
+    let mut visited = vec![false; segments.len()];
+    let mut lines = vec![];
+
+    // Start open lines at their loose ends first, then pick up the loops.
+    let open_ends: Vec<(usize, usize)> = segments.iter().enumerate()
+      .flat_map(|(i, _)| (0..2).map(move |k| (i, k)).collect::<Vec<_>>())
+      .filter(|&(i, k)| by_edge[&segments[i].edges[k]].len() == 1)
+      .collect();
+    let loops = (0..segments.len()).map(|i| (i, 0));
+
+    for (start, k) in open_ends.into_iter().chain(loops) {
+      if visited[start] {
+        continue;
+      }
+
+      let mut line = vec![segments[start].points[k]];
+      let mut cur = start;
+      let mut exit = 1 - k;
+
+      loop {
+        visited[cur] = true;
+        line.push(segments[cur].points[exit]);
+
+        let edge = segments[cur].edges[exit];
+        let next = by_edge[&edge].iter().cloned().find(|&n| n != cur && !visited[n]);
+
+        match next {
+          Some(n) => {
+            exit = if segments[n].edges[0] == edge { 1 } else { 0 };
+            cur = n;
+          },
+          None => break,
+        }
+      }
+
+      lines.push(line);
+    }
+
+    lines
+  }
+
+  fn cell_center(&self, i: usize) -> (f32, f32) {
+    let cs = self.field.options().cell_size;
+    let (x, y) = (i % self.field.width(), i / self.field.width());
+
+    (cs * (2.0 * x as f32 + (y % 2) as f32 + 0.5), cs * (2.0 * y as f32 + 1.0))
+  }
+
+  fn cell_is_water(&self, i: usize) -> bool {
+    let v = tessellate::cell_vertices(i, self.field.width());
+    v.iter().map(|v| self.wet[*v]).sum::<f32>() > 0.0
+  }
+
+  /// Distance from every cell centre to the nearest coastline, negative for
+  /// cells under water. Cells of a map without coastline get `f32::MAX` with
+  /// the sign of their side.
+  pub fn distance_to_shore(&self) -> Vec<f32> {
+    let (w, h) = (self.field.width(), self.field.height());
+    let segments = self.segments();
+    let mut distance = vec![f32::MAX; w * h];
+    let mut nearest = vec![usize::MAX; w * h];
+    let mut queue = BinaryHeap::new();
+
+    let seg_distance = |i: usize, s: &Segment| point_segment_distance(self.cell_center(i), s.points[0], s.points[1]);
+
+    for (k, s) in segments.iter().enumerate() {
+      let d = seg_distance(s.cell, s);
+      if d < distance[s.cell] {
+        distance[s.cell] = d;
+        nearest[s.cell] = k;
+        queue.push(Candidate(d, s.cell));
+      }
+    }
+
+    // Every cell inherits the segment of the neighbour it was reached from,
+    // measured exactly, which keeps the Euclidean error small.
+    while let Some(Candidate(d, i)) = queue.pop() {
+      if d > distance[i] {
+        continue;
+      }
+
+      let (x, y) = ((i % w) as isize, (i / w) as isize);
+      for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)].iter() {
+        let (nx, ny) = (x + dx, y + dy);
+        if nx < 0 || ny < 0 || nx >= w as isize || ny >= h as isize {
+          continue;
+        }
+
+        let n = ny as usize * w + nx as usize;
+        let nd = seg_distance(n, &segments[nearest[i]]);
+        if nd < distance[n] {
+          distance[n] = nd;
+          nearest[n] = nearest[i];
+          queue.push(Candidate(nd, n));
+        }
+      }
+    }
+
+    for (i, d) in distance.iter_mut().enumerate() {
+      if self.cell_is_water(i) {
+        *d = -*d;
+      }
+    }
+
+    distance
+  }
+
+  /// Flat water surface at height `level` over every triangle with a wet
+  /// vertex, as `(x, y)` pairs in the coordinate space of `triangulate_map`.
+  pub fn surface(&self, level: f32) -> Vec<f32> {
+    let mut out = vec![];
+
+    for tri in self.indices.chunks(3) {
+      if tri[0] == tri[1] || !tri.iter().any(|v| self.wet[*v as usize] > 0.0) {
+        continue;
+      }
+
+      for v in tri.iter() {
+        let p = self.position(*v);
+        out.push(p.0);
+        out.push(p.1 - level);
+      }
+    }
+
+    out
+  }
+}
+
+#[derive(PartialEq)]
+struct Candidate(f32, usize);
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+  // Reversed so the max-heap pops the smallest distance first.
+  fn cmp(&self, other: &Candidate) -> Ordering {
+    other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal).then(other.1.cmp(&self.1))
+  }
+}
+
+impl PartialOrd for Candidate {
+  fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+fn point_segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+  let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+  let len2 = dx * dx + dy * dy;
+  let t = if len2 > 0.0 { (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len2).clamp(0.0, 1.0) } else { 0.0 };
+  let (cx, cy) = (a.0 + t * dx, a.1 + t * dy);
+
+  ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()
+}
+
+#[wasm_bindgen]
+impl Water {
+  /// Water below `level`.
+  #[wasm_bindgen(js_name = fromLevel)]
+  pub fn from_level(field: &Heightfield, level: f32) -> Water {
+    Water::below_level(field, level)
+  }
+
+  /// Water wherever a triangle's landscape type, from the per-triangle
+  /// `types_a`/`types_b` layers, is one of `water_types`.
+  #[wasm_bindgen(js_name = fromTypes)]
+  pub fn from_types(field: &Heightfield, types_a: &[u8], types_b: &[u8], water_types: &[u8]) -> Result<Water, JsValue> {
+    let cells = field.width() * field.height();
+    if types_a.len() < cells || types_b.len() < cells {
+      return Err(JsValue::from_str("Water: terrain type layers are smaller than the map."));
+    }
+
+    let flags: Vec<bool> = (0..2 * cells)
+      .map(|t| if t % 2 == 0 { types_a[t / 2] } else { types_b[t / 2] })
+      .map(|ty| water_types.contains(&ty))
+      .collect();
+
+    Water::from_triangles(field, &flags).map_err(JsValue::from_str)
+  }
+
+  /// Coastlines flattened as `[n, x0, y0, ..., xn-1, yn-1, n, ...]`.
+  #[wasm_bindgen(js_name = coastlines)]
+  pub fn js_coastlines(&self) -> Box<[f32]> {
+    let mut out = vec![];
+    for line in self.coastlines() {
+      out.push(line.len() as f32);
+      for p in line {
+        out.push(p.0);
+        out.push(p.1);
+      }
+    }
+    out.into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = distanceToShore)]
+  pub fn js_distance_to_shore(&self) -> Box<[f32]> {
+    self.distance_to_shore().into_boxed_slice()
+  }
+
+  #[wasm_bindgen(js_name = surface)]
+  pub fn js_surface(&self, level: f32) -> Box<[f32]> {
+    self.surface(level).into_boxed_slice()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tessellate::{BorderMode, TessellationOptions};
+
+  /// A 10x10 map at height 2 with a 4x4 lake of height 0 in the middle.
+  fn lake() -> Heightfield {
+    let (w, h) = (10, 10);
+    let elevation: Vec<u8> = (0..w * h).map(|i| {
+      let (x, y) = (i % w, i / w);
+      if (3..7).contains(&x) && (3..7).contains(&y) { 0 } else { 32 }
+    }).collect();
+
+    let options = TessellationOptions { border_mode: BorderMode::Clamp, ..TessellationOptions::default() };
+    Heightfield::from_map(w, h, &elevation, &options).unwrap()
+  }
+
+  #[test]
+  fn test_lake_coastline_is_closed() {
+    let water = Water::below_level(&lake(), 1.0);
+    let lines = water.coastlines();
+
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].first(), lines[0].last());
+    assert!(lines[0].len() > 8);
+  }
+
+  #[test]
+  fn test_distance_to_shore_sign() {
+    let field = lake();
+    let water = Water::below_level(&field, 1.0);
+    let distance = water.distance_to_shore();
+
+    assert!(distance[5 * 10 + 4] < 0.0);
+    assert!(distance[2 * 10 + 1] > 0.0);
+    assert!(distance[5 * 10 + 4].abs() > distance[3 * 10 + 3].abs());
+
+    assert!(!water.surface(1.0).is_empty());
+  }
+}
@@ -15,5 +15,5 @@ wasm_bindgen_test_configure!(run_in_browser);
 
 #[wasm_bindgen_test]
 fn test_tessellate() {
-    lib::triangulate(2, 2, &[0, 0, 0, 0]);
+    lib::triangulate(2, 2, &[0, 0, 0, 0], None);
 }